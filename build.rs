@@ -0,0 +1,96 @@
+//! バックトレースの関数名に使うシンボル表を作る
+//!
+//! 環境変数`KERNEL_SYMBOL_MAP`に`nm -n -C`の出力を置いたファイルを指定すると，
+//! その中の関数を`$OUT_DIR/symbols.bin`に書き出してカーネルに埋め込む
+//! 指定しなければ空の表になり，バックトレースはアドレスだけを表示する
+//!
+//! 表は常に`SYMBOL_TABLE_SIZE`バイトなので，表を入れてビルドし直しても
+//! 関数のアドレスは変わらない．そのため，表を入れないカーネルイメージも256KiBの空の表を持つ
+//!
+//! ```sh
+//! cargo build
+//! nm -n -C target/x86_64-blog_os/debug/blog_os > symbols.txt
+//! KERNEL_SYMBOL_MAP=symbols.txt cargo build
+//! ```
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// `src/backtrace.rs`の`SYMBOL_TABLE_SIZE`と同じでなければならない
+const SYMBOL_TABLE_SIZE: usize = 256 * 1024;
+const MAGIC: &[u8; 4] = b"KSYM";
+/// 表の先頭(マジックと個数)の大きさ
+const HEADER_SIZE: usize = 8;
+/// シンボル1つ分のエントリ(アドレス，名前の位置と長さ)の大きさ
+const ENTRY_SIZE: usize = 16;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=KERNEL_SYMBOL_MAP");
+
+    let mut symbols = Vec::new();
+    if let Some(path) = env::var_os("KERNEL_SYMBOL_MAP") {
+        println!("cargo:rerun-if-changed={}", PathBuf::from(&path).display());
+        let map = fs::read_to_string(&path).expect("failed to read KERNEL_SYMBOL_MAP");
+        symbols = parse_symbol_map(&map);
+    }
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("symbols.bin");
+    fs::write(out, build_table(&symbols)).expect("failed to write symbol table");
+}
+
+/// `nm -n -C`の出力から関数(テキストセクションのシンボル)を取り出す
+fn parse_symbol_map(map: &str) -> Vec<(u64, String)> {
+    let mut symbols: Vec<(u64, String)> = map
+        .lines()
+        .filter_map(|line| {
+            let (addr, rest) = line.split_once(' ')?;
+            let (kind, name) = rest.split_once(' ')?;
+            if !matches!(kind, "T" | "t" | "W" | "w") {
+                return None;
+            }
+            let addr = u64::from_str_radix(addr, 16).ok()?;
+            Some((addr, name.to_string()))
+        })
+        .collect();
+    symbols.sort_by_key(|(addr, _)| *addr);
+    symbols.dedup_by_key(|(addr, _)| *addr);
+    symbols
+}
+
+/// 表を作る．入りきらないシンボルは捨てる
+///
+/// `KSYM`，個数(u32)，アドレス順のエントリ(アドレスu64，名前の位置u32，長さu32)，名前の順に並べる
+fn build_table(symbols: &[(u64, String)]) -> Vec<u8> {
+    let mut count = 0;
+    let mut names_size = 0;
+    for (_, name) in symbols {
+        let size = HEADER_SIZE + (count + 1) * ENTRY_SIZE + names_size + name.len();
+        if size > SYMBOL_TABLE_SIZE {
+            println!(
+                "cargo:warning=symbol table is full, dropping {} symbols",
+                symbols.len() - count
+            );
+            break;
+        }
+        count += 1;
+        names_size += name.len();
+    }
+
+    let mut table = Vec::with_capacity(SYMBOL_TABLE_SIZE);
+    table.extend_from_slice(MAGIC);
+    table.extend_from_slice(&(count as u32).to_le_bytes());
+    let mut name_offset = HEADER_SIZE + count * ENTRY_SIZE;
+    for (addr, name) in &symbols[..count] {
+        table.extend_from_slice(&addr.to_le_bytes());
+        table.extend_from_slice(&(name_offset as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        name_offset += name.len();
+    }
+    for (_, name) in &symbols[..count] {
+        table.extend_from_slice(name.as_bytes());
+    }
+    table.resize(SYMBOL_TABLE_SIZE, 0);
+    table
+}
@@ -0,0 +1,281 @@
+use crate::memory;
+use core::mem;
+use core::ptr;
+use x86_64::PhysAddr;
+
+/// MADTに書かれていても扱わないI/O APICの数の上限
+pub const MAX_IO_APICS: usize = 8;
+/// 記録するプロセッサ(Local APIC)の数の上限
+pub const MAX_PROCESSORS: usize = 64;
+/// ISA IRQの数
+pub const ISA_IRQS: usize = 16;
+
+/// ACPIのテーブルを読むときのエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// `memory::init`の前に呼び出された
+    MemoryNotInitialized,
+    /// BIOS領域にRSDPが見つからない
+    RsdpNotFound,
+    /// チェックサムが合わないテーブル(シグネチャ)
+    InvalidChecksum([u8; 4]),
+    /// RSDT/XSDTにMADTが無い
+    MadtNotFound,
+}
+
+/// RSDP(Root System Description Pointer)
+/// ACPI 2.0以降では後ろにXSDTのアドレスなどが続く
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// 全てのシステム記述テーブルに共通のヘッダ
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// MADT(Multiple APIC Description Table)のエントリの種類
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// I/O APIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// このI/O APICの最初の入力が受け持つGSI(Global System Interrupt)番号
+    pub gsi_base: u32,
+}
+
+/// ISA IRQとGSIの対応が既定(同じ番号・high active・エッジトリガ)と異なるもの
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPSのINTIフラグ(極性とトリガモード)
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+/// ISA IRQがI/O APICのどの入力にどう届くか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqRoute {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// MADTから読み取った割り込みコントローラの構成
+#[derive(Debug, Clone)]
+pub struct ApicInfo {
+    pub local_apic_address: PhysAddr,
+    /// 有効なプロセッサのLocal APIC ID
+    pub local_apic_ids: [Option<u8>; MAX_PROCESSORS],
+    pub io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    pub overrides: [Option<InterruptOverride>; ISA_IRQS],
+    /// 8259 PICも搭載されているか(PCAT_COMPAT)
+    pub has_legacy_pics: bool,
+}
+
+impl ApicInfo {
+    pub fn io_apics(&self) -> impl Iterator<Item = &IoApicInfo> {
+        self.io_apics.iter().flatten()
+    }
+
+    /// ISA IRQ `irq`が届くGSIと，その極性・トリガモード
+    pub fn irq_route(&self, irq: u8) -> IrqRoute {
+        let over = self.overrides.iter().flatten().find(|o| o.irq == irq);
+        match over {
+            Some(over) => IrqRoute {
+                gsi: over.gsi,
+                active_low: over.active_low(),
+                level_triggered: over.level_triggered(),
+            },
+            None => IrqRoute {
+                gsi: u32::from(irq),
+                active_low: false,
+                level_triggered: false,
+            },
+        }
+    }
+}
+
+/// BIOS領域からRSDPを探し，MADTを読んで割り込みコントローラの構成を返す
+///
+/// ACPIのテーブルは物理メモリのマッピング越しに読む
+pub fn find_apic_info() -> Result<ApicInfo, AcpiError> {
+    memory::physical_memory_offset().ok_or(AcpiError::MemoryNotInitialized)?;
+    let rsdp = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let madt = find_table(&rsdp, *b"APIC")?.ok_or(AcpiError::MadtNotFound)?;
+    Ok(parse_madt(madt))
+}
+
+/// 拡張BIOSデータ領域の先頭1KiBと，0xE0000から0xFFFFFまでを16バイトごとに探す
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda = u64::from(unsafe { read_phys::<u16>(PhysAddr::new(0x40e)) }) << 4;
+    let candidates = (ebda..ebda + 1024)
+        .step_by(16)
+        .chain((0xe_0000..0x10_0000).step_by(16));
+    for addr in candidates {
+        let addr = PhysAddr::new(addr);
+        let rsdp: Rsdp = unsafe { read_phys(addr) };
+        if &rsdp.signature != b"RSD PTR " || !checksum_ok(addr, 20) {
+            continue;
+        }
+        if rsdp.revision >= 2 && !checksum_ok(addr, rsdp.length as usize) {
+            continue;
+        }
+        return Some(rsdp);
+    }
+    None
+}
+
+/// RSDT(ACPI 2.0以降ではXSDT)から`signature`のテーブルを探し，その物理アドレスを返す
+fn find_table(rsdp: &Rsdp, signature: [u8; 4]) -> Result<Option<PhysAddr>, AcpiError> {
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(u64::from(rsdp.rsdt_address)), 4)
+    };
+    let header = read_table_header(root)?;
+    let entries = (header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+
+    for index in 0..entries {
+        let entry_addr = root + mem::size_of::<SdtHeader>() + index * entry_size;
+        let table = if entry_size == 8 {
+            unsafe { read_phys::<u64>(entry_addr) }
+        } else {
+            u64::from(unsafe { read_phys::<u32>(entry_addr) })
+        };
+        let table = PhysAddr::new(table);
+        let table_header: SdtHeader = unsafe { read_phys(table) };
+        if table_header.signature == signature {
+            read_table_header(table)?;
+            return Ok(Some(table));
+        }
+    }
+    Ok(None)
+}
+
+/// テーブルのヘッダを読み，チェックサムを確かめる
+fn read_table_header(addr: PhysAddr) -> Result<SdtHeader, AcpiError> {
+    let header: SdtHeader = unsafe { read_phys(addr) };
+    if checksum_ok(addr, header.length as usize) {
+        Ok(header)
+    } else {
+        Err(AcpiError::InvalidChecksum(header.signature))
+    }
+}
+
+fn parse_madt(madt: PhysAddr) -> ApicInfo {
+    let header: SdtHeader = unsafe { read_phys(madt) };
+    let body = madt + mem::size_of::<SdtHeader>();
+    let mut info = ApicInfo {
+        local_apic_address: PhysAddr::new(u64::from(unsafe { read_phys::<u32>(body) })),
+        local_apic_ids: [None; MAX_PROCESSORS],
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; ISA_IRQS],
+        has_legacy_pics: unsafe { read_phys::<u32>(body + 4u64) } & 1 != 0,
+    };
+
+    // ヘッダ，Local APICのアドレスとフラグの後に可変長のエントリが並ぶ
+    let end = madt + u64::from(header.length);
+    let mut entry = body + 8u64;
+    while entry + 2u64 <= end {
+        let (kind, length) = unsafe { (read_phys::<u8>(entry), read_phys::<u8>(entry + 1u64)) };
+        if length < 2 {
+            break;
+        }
+        match kind {
+            MADT_LOCAL_APIC => {
+                let apic_id = unsafe { read_phys::<u8>(entry + 3u64) };
+                let flags = unsafe { read_phys::<u32>(entry + 4u64) };
+                // ビット0: 有効，ビット1: 後から有効にできる
+                if flags & 0b11 != 0 {
+                    insert(&mut info.local_apic_ids, apic_id);
+                }
+            }
+            MADT_IO_APIC => unsafe {
+                insert(
+                    &mut info.io_apics,
+                    IoApicInfo {
+                        id: read_phys(entry + 2u64),
+                        address: PhysAddr::new(u64::from(read_phys::<u32>(entry + 4u64))),
+                        gsi_base: read_phys(entry + 8u64),
+                    },
+                );
+            },
+            MADT_INTERRUPT_OVERRIDE => unsafe {
+                insert(
+                    &mut info.overrides,
+                    InterruptOverride {
+                        irq: read_phys(entry + 3u64),
+                        gsi: read_phys(entry + 4u64),
+                        flags: read_phys(entry + 8u64),
+                    },
+                );
+            },
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                info.local_apic_address = PhysAddr::new(unsafe { read_phys(entry + 4u64) });
+            }
+            _ => {}
+        }
+        entry += u64::from(length);
+    }
+    info
+}
+
+/// 空いている最初の場所に`value`を入れる(一杯なら捨てる)
+fn insert<T>(slots: &mut [Option<T>], value: T) {
+    if let Some(slot) = slots.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(value);
+    }
+}
+
+/// `addr`から`len`バイトの和が0になっているか
+fn checksum_ok(addr: PhysAddr, len: usize) -> bool {
+    let sum = (0..len as u64).fold(0u8, |sum, offset| {
+        sum.wrapping_add(unsafe { read_phys::<u8>(addr + offset) })
+    });
+    sum == 0
+}
+
+/// 物理アドレス`addr`から`T`を読む(アラインされていなくてもよい)
+///
+/// この関数はunsafeである：呼び出し元は`memory::init`の後であることと，
+/// そこに`T`として読める値があることを保証しなければならない
+unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    let offset = memory::physical_memory_offset().expect("memory is not initialized");
+    ptr::read_unaligned((offset + addr.as_u64()).as_ptr())
+}
@@ -1,27 +1,70 @@
+use crate::memory;
+use crate::serial_println;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
-use linked_list_allocator::LockedHeap;
+use fixed_size_block::FixedSizeBlockAllocator;
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        Size4KiB,
     },
     VirtAddr,
 };
 
+pub mod fixed_size_block;
+pub mod slab;
+pub mod stats;
+
+pub use slab::{SlabBox, SlabCache, SlabUsage};
+pub use stats::{Allocation, HeapStats, LeakReport};
+
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB
+/// ヒープが拡張できる仮想アドレス範囲の上限
+/// `HEAP_START`から`HEAP_START + HEAP_MAX_SIZE`までをヒープのために予約する
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64MiB
+/// 一度の拡張でマップする最小のサイズ
+/// 小さな割り当てのたびにページをマップし直さないようにする
+const HEAP_GROW_MIN: usize = 64 * 1024; // 64KiB
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), MapToError<Size4KiB>> {
+    map_heap_pages(HEAP_START, HEAP_SIZE, mapper, frame_allocator)?;
+
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+
+    // ヒープの予約範囲の前後はマップしないので，ガードページとして登録しておく
+    memory::region::register(
+        "kernel heap",
+        VirtAddr::new(HEAP_START as u64),
+        VirtAddr::new((HEAP_START + HEAP_MAX_SIZE) as u64),
+    )
+    .expect("failed to register heap region");
+    Ok(())
+}
+
+/// `start`から`size`バイトのヒープ領域に物理フレームをマップする
+///
+/// 途中で失敗した場合は，それまでにマップしたページを外してフレームを返してから`Err`を返す
+/// (マップされたページが残ると，次に同じ場所へ拡張しようとしたときに必ず失敗するため)
+fn map_heap_pages(
+    start: usize,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
 ) -> Result<(), MapToError<Size4KiB>> {
     // ページ範囲の作成
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
@@ -30,18 +73,117 @@ pub fn init_heap(
     // ページへのマッピング
     // ページがマップされるべき物理フレームを割り当てる
     // PRESENTフラグとWRITABLEフラグをセットし，メモリへの読み書きを許可する
+    // ヒープのデータを命令として実行できないよう，NO_EXECUTEも立てる
     for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        let result = match frame_allocator.allocate_frame() {
+            Some(frame) => {
+                let flags =
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+                match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                    Ok(flush) => {
+                        flush.flush();
+                        Ok(())
+                    }
+                    Err(err) => {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                        Err(err)
+                    }
+                }
+            }
+            None => Err(MapToError::FrameAllocationFailed),
+        };
+        if let Err(err) = result {
+            // まだ誰も使っていないページなので，外してフレームを返してよい
+            for mapped in Page::range(page_range.start, page) {
+                if let Ok((frame, flush)) = mapper.unmap(mapped) {
+                    flush.flush();
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+            return Err(err);
+        }
     }
+    Ok(())
+}
 
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+/// `layout`の割り当てが入るだけヒープの末尾にページを追加でマップする
+///
+/// ヒープアロケータのロックを保持した状態で呼ばれる
+/// まず`HEAP_GROW_MIN`以上をマップしようとし，フレームが足りなければ必要な分だけで再度試す
+/// `HEAP_MAX_SIZE`を超える場合や，ページテーブル・フレームアロケータが
+/// 使えない場合は`false`を返す
+fn grow_heap(heap: &mut linked_list_allocator::Heap, layout: Layout) -> bool {
+    const PAGE_SIZE: usize = 4096;
+
+    let heap_top = heap.top();
+    let heap_limit = HEAP_START + HEAP_MAX_SIZE;
+    // アラインメントのための余白も含めて，ページ単位に切り上げる
+    let required = align_up(layout.size() + layout.align(), PAGE_SIZE);
+    let grow_size = required.max(HEAP_GROW_MIN).min(heap_limit - heap_top);
+    if grow_size < required {
+        return false;
     }
-    Ok(())
+
+    let mapped = memory::with_kernel_memory(|kernel_memory| {
+        let mut map = |size| {
+            map_heap_pages(
+                heap_top,
+                size,
+                &mut kernel_memory.mapper,
+                &mut kernel_memory.frame_allocator,
+            )
+            .map(|()| size)
+        };
+        map(grow_size).or_else(|_| map(required))
+    });
+    match mapped {
+        Some(Ok(size)) => {
+            unsafe { heap.extend(size) };
+            true
+        }
+        _ => false,
+    }
+}
+
+/// グローバルアロケータの使用状況を返す
+pub fn stats() -> HeapStats {
+    interrupts::without_interrupts(|| ALLOCATOR.lock().stats())
+}
+
+/// ヒープと，全てのスラブキャッシュの使用状況をシリアルポートに出力する
+pub fn print_usage() {
+    let heap = stats();
+    serial_println!(
+        "heap: {} of {} bytes in use (peak {}), {} bytes free, largest free block {}",
+        heap.bytes_in_use,
+        heap.heap_size,
+        heap.peak_bytes_in_use,
+        heap.free_bytes,
+        heap.largest_free_block
+    );
+    slab::for_each_cache(|usage| {
+        serial_println!("{}", usage);
+    });
+}
+
+/// 割り当てを記録し始める
+///
+/// `stop_leak_tracking`までに割り当てられ，解放されなかったものを報告できるようにする
+/// テストで，ある処理が割り当てたものを全て解放したことを確かめるために使う
+pub fn start_leak_tracking() {
+    interrupts::without_interrupts(|| ALLOCATOR.lock().start_leak_tracking());
+}
+
+/// 記録を止め，`start_leak_tracking`以降に割り当てられて解放されていないものを返す
+pub fn stop_leak_tracking() -> LeakReport {
+    interrupts::without_interrupts(|| ALLOCATOR.lock().stop_leak_tracking())
+}
+
+/// 与えられたアドレス`addr`を`align`に上丸めする
+///
+/// `align`は2の累乗でなければならない
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
 }
 
 pub struct Dummy;
@@ -55,3 +197,23 @@ unsafe impl GlobalAlloc for Dummy {
         panic!("dealloc should be never called")
     }
 }
+
+/// トレイト実装を許可するためのspin::Mutexのラッパー
+///
+/// 外部クレートの型に外部のトレイト(GlobalAlloc)を実装することはできないので，
+/// 自クレートの型で包む
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
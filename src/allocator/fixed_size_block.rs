@@ -0,0 +1,189 @@
+use super::stats::{HeapAccounting, HeapStats, LeakReport};
+use super::Locked;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{
+    mem,
+    ptr::{self, NonNull},
+};
+
+/// 使用するブロックサイズ
+///
+/// ブロックのアラインメントとしても使うので，全て2の累乗でなければならない
+/// (ブロックのアラインメントは常にブロックサイズと同じにする)
+/// 8より小さいブロックは次のブロックへのポインタ(64bit)を格納できないので使えない
+/// 2048バイトより大きい割り当てはフォールバックアロケータに任せる
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// 未使用ブロックの連結リストのノード
+///
+/// 未使用のブロック自体の中に格納する
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// サイズごとの未使用ブロックリストを持つアロケータ
+///
+/// 割り当て・解放はリストの先頭を付け替えるだけなのでO(1)で済む
+/// どのサイズにも収まらない大きな割り当ては連結リストのヒープに任せる
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: linked_list_allocator::Heap,
+    accounting: HeapAccounting,
+}
+
+impl FixedSizeBlockAllocator {
+    /// 空のFixedSizeBlockAllocatorを作る
+    pub const fn new() -> Self {
+        // 配列の初期化式はCopyな値か定数でなければならないので定数を経由する
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: linked_list_allocator::Heap::empty(),
+            accounting: HeapAccounting::new(),
+        }
+    }
+
+    /// 与えられたヒープ境界でアロケータを初期化する
+    ///
+    /// この関数はunsafeである：呼び出し元は与えるヒープ境界が有効で
+    /// ヒープが未使用であることを保証しなければならない
+    /// また，この関数は一度しか呼び出してはならない
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// フォールバックアロケータを使って割り当てを行う
+    ///
+    /// ヒープが足りなければ，ページを追加でマップしてヒープを拡張してから再度試す
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        if !super::grow_heap(&mut self.fallback_allocator, layout) {
+            return ptr::null_mut();
+        }
+        match self.fallback_allocator.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    /// ヒープの使用状況を集計する
+    pub fn stats(&mut self) -> HeapStats {
+        // 再利用を待っているブロックも空き領域として数える
+        let mut free_bytes = self.fallback_allocator.free();
+        let mut largest_free_block = self.largest_fallback_block();
+        for (index, head) in self.list_heads.iter().enumerate() {
+            let mut count = 0;
+            let mut node = head.as_deref();
+            while let Some(n) = node {
+                count += 1;
+                node = n.next.as_deref();
+            }
+            free_bytes += count * BLOCK_SIZES[index];
+            if count > 0 {
+                largest_free_block = largest_free_block.max(BLOCK_SIZES[index]);
+            }
+        }
+        self.accounting.stats(
+            self.fallback_allocator.size(),
+            free_bytes,
+            largest_free_block,
+        )
+    }
+
+    /// フォールバックヒープから一度に割り当てられる最大のサイズを求める
+    ///
+    /// linked_list_allocatorは空き領域の一覧を公開していないので，
+    /// 実際に割り当てと解放を試しながら二分探索する
+    fn largest_fallback_block(&mut self) -> usize {
+        const ALIGN: usize = mem::align_of::<usize>();
+
+        // lo は割り当てられるサイズ，hi は割り当てられないサイズ
+        let mut lo = 0;
+        let mut hi = self.fallback_allocator.free() / ALIGN + 1;
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            let layout = Layout::from_size_align(mid * ALIGN, ALIGN).unwrap();
+            match self.fallback_allocator.allocate_first_fit(layout) {
+                Ok(ptr) => {
+                    unsafe { self.fallback_allocator.deallocate(ptr, layout) };
+                    lo = mid;
+                }
+                Err(_) => hi = mid,
+            }
+        }
+        lo * ALIGN
+    }
+
+    /// 割り当てと解放の記録を始める
+    pub fn start_leak_tracking(&mut self) {
+        self.accounting.start_leak_tracking();
+    }
+
+    /// 記録を止め，記録を始めてから解放されなかった割り当てを返す
+    pub fn stop_leak_tracking(&mut self) -> LeakReport {
+        self.accounting.stop_leak_tracking()
+    }
+}
+
+/// 与えられたレイアウトに対して適切なブロックサイズを選ぶ
+///
+/// `BLOCK_SIZES`配列のインデックスを返す
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        let ptr = match list_index(&layout) {
+            Some(index) => {
+                match allocator.list_heads[index].take() {
+                    Some(node) => {
+                        // リストの先頭のブロックを取り出して返す
+                        allocator.list_heads[index] = node.next.take();
+                        node as *mut ListNode as *mut u8
+                    }
+                    None => {
+                        // リストにブロックがないので新しいブロックを割り当てる
+                        // 全てのブロックサイズは2の累乗なのでアラインメントにもそのまま使える
+                        let block_size = BLOCK_SIZES[index];
+                        let block_align = block_size;
+                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                        allocator.fallback_alloc(layout)
+                    }
+                }
+            }
+            None => allocator.fallback_alloc(layout),
+        };
+        if !ptr.is_null() {
+            allocator.accounting.record_alloc(ptr, layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        allocator.accounting.record_dealloc(ptr, layout);
+        match list_index(&layout) {
+            Some(index) => {
+                // 解放されたブロックをリストの先頭に追加する
+                let new_node = ListNode {
+                    next: allocator.list_heads[index].take(),
+                };
+                // ブロックがノードを格納するのに十分なサイズとアラインメントを持つことを確認
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                allocator.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                allocator.fallback_allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+}
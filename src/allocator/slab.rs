@@ -0,0 +1,389 @@
+use crate::memory::{self, with_kernel_memory};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// スラブ1つの大きさ(物理フレーム1つ分)
+const SLAB_SIZE: usize = 4096;
+
+/// 解放せずに取っておく空のスラブの数
+const MAX_EMPTY_SLABS: usize = 1;
+
+/// 使用状況を報告できるキャッシュの数
+const MAX_CACHES: usize = 32;
+
+/// スラブの先頭に置くヘッダ
+///
+/// スラブはページ境界にアラインされているので，オブジェクトのアドレスの
+/// 下位12ビットを落とせばヘッダが見つかる
+struct SlabHeader {
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    /// 空きオブジェクトのリストの先頭
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+/// 空いているオブジェクトの位置に置く，空きリストのノード
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// スラブの双方向連結リスト
+struct SlabList {
+    head: *mut SlabHeader,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        SlabList {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut SlabHeader) {
+        let (next, prev) = ((*slab).next, (*slab).prev);
+        if prev.is_null() {
+            self.head = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        self.len -= 1;
+    }
+}
+
+/// ロックの中で扱う，キャッシュのスラブの一覧
+struct Slabs {
+    partial: SlabList,
+    full: SlabList,
+    empty: SlabList,
+    objects_in_use: usize,
+}
+
+// スラブへのポインタはキャッシュのロックを通してだけ使う
+unsafe impl Send for Slabs {}
+
+/// 型`T`のオブジェクトを，フレーム単位のスラブから割り当てるキャッシュ
+///
+/// 同じレイアウトで何度も割り当てられるカーネルのオブジェクト向け
+/// スラブは使用中のオブジェクトの数によって部分的(partial)・満杯(full)・空(empty)の
+/// リストに分けて管理し，割り当ては部分的なスラブから優先して行う
+/// キャッシュは`static`に置いて使う
+pub struct SlabCache<T> {
+    name: &'static str,
+    slabs: Mutex<Slabs>,
+    /// 使用状況の一覧に登録済みか
+    registered: AtomicBool,
+    /// 型`T`の値そのものは持たないので，`T`に関わらずSend・Syncにする
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> SlabCache<T> {
+    /// 1つのオブジェクトが占めるバイト数(空きリストのノードが入る大きさにする)
+    const SLOT_SIZE: usize = align_up(
+        max(mem::size_of::<T>(), mem::size_of::<FreeObject>()),
+        Self::SLOT_ALIGN,
+    );
+    const SLOT_ALIGN: usize = max(mem::align_of::<T>(), mem::align_of::<FreeObject>());
+    /// スラブの先頭から最初のオブジェクトまでのバイト数
+    const FIRST_OBJECT: usize = align_up(mem::size_of::<SlabHeader>(), Self::SLOT_ALIGN);
+    const OBJECTS_PER_SLAB: usize = if Self::FIRST_OBJECT < SLAB_SIZE {
+        (SLAB_SIZE - Self::FIRST_OBJECT) / Self::SLOT_SIZE
+    } else {
+        0
+    };
+
+    pub const fn new(name: &'static str) -> Self {
+        assert!(Self::OBJECTS_PER_SLAB > 0, "object does not fit in a slab");
+        SlabCache {
+            name,
+            slabs: Mutex::new(Slabs {
+                partial: SlabList::new(),
+                full: SlabList::new(),
+                empty: SlabList::new(),
+                objects_in_use: 0,
+            }),
+            registered: AtomicBool::new(false),
+            _marker: PhantomData,
+        }
+    }
+
+    /// `value`をキャッシュから割り当てた場所に移す
+    ///
+    /// 新しいスラブのためのフレームが割り当てられなかった場合は`None`を返す
+    pub fn alloc(&'static self, value: T) -> Option<SlabBox<T>> {
+        let ptr = self.allocate()?;
+        unsafe { ptr.as_ptr().write(value) };
+        Some(SlabBox { ptr, cache: self })
+    }
+
+    /// 初期化されていないオブジェクト1つ分の領域を割り当てる
+    pub fn allocate(&'static self) -> Option<NonNull<T>> {
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            register(self);
+        }
+        interrupts::without_interrupts(|| {
+            let mut slabs = self.slabs.lock();
+            let slab = if !slabs.partial.head.is_null() {
+                slabs.partial.head
+            } else {
+                let slab = if !slabs.empty.head.is_null() {
+                    let slab = slabs.empty.head;
+                    unsafe { slabs.empty.remove(slab) };
+                    slab
+                } else {
+                    self.new_slab()?
+                };
+                unsafe { slabs.partial.push(slab) };
+                slab
+            };
+
+            unsafe {
+                let object = (*slab).free;
+                (*slab).free = (*object).next;
+                (*slab).in_use += 1;
+                if (*slab).in_use == Self::OBJECTS_PER_SLAB {
+                    slabs.partial.remove(slab);
+                    slabs.full.push(slab);
+                }
+                slabs.objects_in_use += 1;
+                NonNull::new(object.cast())
+            }
+        })
+    }
+
+    /// `allocate`で割り当てた領域を返す
+    ///
+    /// この関数はunsafeである：呼び出し元は`ptr`がこのキャッシュの`allocate`で
+    /// 割り当てられたもので，もう使われていないことを保証しなければならない
+    /// `T`のdropは行わない
+    pub unsafe fn deallocate(&self, ptr: NonNull<T>) {
+        interrupts::without_interrupts(|| {
+            let mut slabs = self.slabs.lock();
+            let object: *mut FreeObject = ptr.as_ptr().cast();
+            let slab = (object as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader;
+
+            if (*slab).in_use == Self::OBJECTS_PER_SLAB {
+                slabs.full.remove(slab);
+                slabs.partial.push(slab);
+            }
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+            slabs.objects_in_use -= 1;
+
+            if (*slab).in_use == 0 {
+                slabs.partial.remove(slab);
+                if slabs.empty.len < MAX_EMPTY_SLABS {
+                    slabs.empty.push(slab);
+                } else {
+                    free_slab(slab);
+                }
+            }
+        });
+    }
+
+    /// 空のスラブを全てフレームアロケータに返す
+    pub fn shrink(&self) {
+        interrupts::without_interrupts(|| {
+            let mut slabs = self.slabs.lock();
+            while !slabs.empty.head.is_null() {
+                let slab = slabs.empty.head;
+                unsafe {
+                    slabs.empty.remove(slab);
+                    free_slab(slab);
+                }
+            }
+        });
+    }
+
+    pub fn usage(&self) -> SlabUsage {
+        interrupts::without_interrupts(|| {
+            let slabs = self.slabs.lock();
+            SlabUsage {
+                name: self.name,
+                object_size: Self::SLOT_SIZE,
+                objects_per_slab: Self::OBJECTS_PER_SLAB,
+                partial_slabs: slabs.partial.len,
+                full_slabs: slabs.full.len,
+                empty_slabs: slabs.empty.len,
+                objects_in_use: slabs.objects_in_use,
+            }
+        })
+    }
+
+    /// 新しいフレームをスラブにし，全てのオブジェクトを空きリストに入れる
+    fn new_slab(&self) -> Option<*mut SlabHeader> {
+        let frame: PhysFrame =
+            with_kernel_memory(|kernel_memory| kernel_memory.frame_allocator.allocate_frame())??;
+        let physical_memory_offset = memory::physical_memory_offset()?;
+        let base = physical_memory_offset + frame.start_address().as_u64();
+        let slab: *mut SlabHeader = base.as_mut_ptr();
+
+        let mut free = ptr::null_mut();
+        for index in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let object: *mut FreeObject =
+                (base + (Self::FIRST_OBJECT + index * Self::SLOT_SIZE) as u64).as_mut_ptr();
+            unsafe { object.write(FreeObject { next: free }) };
+            free = object;
+        }
+        unsafe {
+            slab.write(SlabHeader {
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+                free,
+                in_use: 0,
+            })
+        };
+        Some(slab)
+    }
+}
+
+/// スラブのフレームをフレームアロケータに返す
+unsafe fn free_slab(slab: *mut SlabHeader) {
+    let physical_memory_offset = memory::physical_memory_offset().unwrap();
+    let phys = VirtAddr::from_ptr(slab) - physical_memory_offset;
+    let frame: PhysFrame = PhysFrame::containing_address(PhysAddr::new(phys));
+    with_kernel_memory(|kernel_memory| kernel_memory.frame_allocator.deallocate_frame(frame))
+        .expect("kernel memory is not available");
+}
+
+/// `SlabCache`から割り当てた`T`を所有するポインタ
+///
+/// dropすると`T`をdropし，領域をキャッシュに返す
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache<T>,
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.deallocate(self.ptr);
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+/// スラブキャッシュの使用状況
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabUsage {
+    pub name: &'static str,
+    /// オブジェクト1つが占めるバイト数
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub partial_slabs: usize,
+    pub full_slabs: usize,
+    pub empty_slabs: usize,
+    pub objects_in_use: usize,
+}
+
+impl SlabUsage {
+    pub fn slabs(&self) -> usize {
+        self.partial_slabs + self.full_slabs + self.empty_slabs
+    }
+}
+
+impl fmt::Display for SlabUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "slab `{}`: {} x {} bytes in use, slabs {} partial / {} full / {} empty",
+            self.name,
+            self.objects_in_use,
+            self.object_size,
+            self.partial_slabs,
+            self.full_slabs,
+            self.empty_slabs
+        )
+    }
+}
+
+/// 使用状況を報告するためのキャッシュの一覧
+trait CacheUsage: Sync {
+    fn usage(&self) -> SlabUsage;
+}
+
+impl<T> CacheUsage for SlabCache<T> {
+    fn usage(&self) -> SlabUsage {
+        SlabCache::usage(self)
+    }
+}
+
+static CACHES: Mutex<[Option<&'static dyn CacheUsage>; MAX_CACHES]> =
+    Mutex::new([None; MAX_CACHES]);
+
+/// 最初の割り当てのときにキャッシュを一覧に登録する(一覧が一杯なら報告しない)
+fn register(cache: &'static dyn CacheUsage) {
+    interrupts::without_interrupts(|| {
+        if let Some(slot) = CACHES.lock().iter_mut().find(|c| c.is_none()) {
+            *slot = Some(cache);
+        }
+    });
+}
+
+/// 一度でも割り当てに使われた全てのキャッシュの使用状況を`f`に渡す
+pub fn for_each_cache(mut f: impl FnMut(&SlabUsage)) {
+    interrupts::without_interrupts(|| {
+        for cache in CACHES.lock().iter().flatten() {
+            f(&cache.usage());
+        }
+    });
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
@@ -0,0 +1,166 @@
+use alloc::alloc::Layout;
+use core::fmt;
+
+/// リークの追跡で同時に記録できる割り当ての数
+///
+/// 割り当て中にヒープを使うことはできないので，固定長の表に記録する
+pub const LEAK_TRACKER_CAPACITY: usize = 128;
+
+/// ヒープの使用状況
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// ヒープとしてマップされているバイト数
+    pub heap_size: usize,
+    /// 割り当て中のバイト数(要求されたレイアウトのサイズの合計)
+    pub bytes_in_use: usize,
+    /// `bytes_in_use`の最大値
+    pub peak_bytes_in_use: usize,
+    /// 再利用を待つブロックとフォールバックヒープの空き領域の合計
+    pub free_bytes: usize,
+    /// 一度に割り当てられる最大の空きブロックのバイト数
+    /// `free_bytes`より極端に小さければ断片化している
+    pub largest_free_block: usize,
+    /// これまでの割り当て回数
+    pub allocations: usize,
+    /// これまでの解放回数
+    pub deallocations: usize,
+}
+
+/// 割り当て中のメモリ領域
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub addr: usize,
+    pub layout: Layout,
+}
+
+/// リークの追跡中に解放されなかった割り当ての一覧
+#[derive(Clone, Copy)]
+pub struct LeakReport {
+    entries: [Option<Allocation>; LEAK_TRACKER_CAPACITY],
+    /// 表が溢れて記録できなかった割り当てがあったかどうか
+    overflowed: bool,
+}
+
+impl LeakReport {
+    /// 解放されなかった割り当てがなければtrue
+    pub fn is_empty(&self) -> bool {
+        !self.overflowed && self.iter().next().is_none()
+    }
+
+    /// 表が溢れて全ての割り当てを記録できなかった場合はtrue
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// 解放されなかった割り当ての数(記録できたものだけ)
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Allocation> {
+        self.entries.iter().flatten()
+    }
+}
+
+impl fmt::Debug for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LeakReport")
+            .field("outstanding", &self.len())
+            .field("overflowed", &self.overflowed)
+            .finish()?;
+        for allocation in self.iter() {
+            write!(f, "\n  {:#x}: {:?}", allocation.addr, allocation.layout)?;
+        }
+        Ok(())
+    }
+}
+
+/// アロケータのロックの中で更新される割り当ての記録
+pub(super) struct HeapAccounting {
+    bytes_in_use: usize,
+    peak_bytes_in_use: usize,
+    allocations: usize,
+    deallocations: usize,
+    leak_tracking: bool,
+    leaks: LeakReport,
+}
+
+impl HeapAccounting {
+    pub(super) const fn new() -> Self {
+        const NONE: Option<Allocation> = None;
+        HeapAccounting {
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+            allocations: 0,
+            deallocations: 0,
+            leak_tracking: false,
+            leaks: LeakReport {
+                entries: [NONE; LEAK_TRACKER_CAPACITY],
+                overflowed: false,
+            },
+        }
+    }
+
+    pub(super) fn record_alloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.allocations += 1;
+        self.bytes_in_use += layout.size();
+        self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+
+        if self.leak_tracking {
+            let allocation = Allocation {
+                addr: ptr as usize,
+                layout,
+            };
+            match self.leaks.entries.iter_mut().find(|e| e.is_none()) {
+                Some(entry) => *entry = Some(allocation),
+                None => self.leaks.overflowed = true,
+            }
+        }
+    }
+
+    pub(super) fn record_dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.deallocations += 1;
+        self.bytes_in_use -= layout.size();
+
+        if self.leak_tracking {
+            // 追跡を始める前の割り当ては表にないので，見つからなくても問題ない
+            let addr = ptr as usize;
+            if let Some(entry) = self
+                .leaks
+                .entries
+                .iter_mut()
+                .find(|e| matches!(e, Some(a) if a.addr == addr))
+            {
+                *entry = None;
+            }
+        }
+    }
+
+    pub(super) fn start_leak_tracking(&mut self) {
+        self.leak_tracking = true;
+        self.leaks = HeapAccounting::new().leaks;
+    }
+
+    pub(super) fn stop_leak_tracking(&mut self) -> LeakReport {
+        self.leak_tracking = false;
+        self.leaks
+    }
+
+    /// 割り当ての記録と，アロケータが数えた空き領域から統計を作る
+    pub(super) fn stats(
+        &self,
+        heap_size: usize,
+        free_bytes: usize,
+        largest_free_block: usize,
+    ) -> HeapStats {
+        HeapStats {
+            heap_size,
+            bytes_in_use: self.bytes_in_use,
+            peak_bytes_in_use: self.peak_bytes_in_use,
+            free_bytes,
+            largest_free_block,
+            allocations: self.allocations,
+            deallocations: self.deallocations,
+        }
+    }
+}
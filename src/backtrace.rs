@@ -0,0 +1,242 @@
+use crate::memory::read_checked;
+use core::arch::asm;
+use core::fmt;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// 記録するフレームの最大数
+const MAX_FRAMES: usize = 32;
+
+/// `build.rs`の`SYMBOL_TABLE_SIZE`と同じでなければならない
+const SYMBOL_TABLE_SIZE: usize = 256 * 1024;
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 16;
+
+/// `build.rs`が作ったシンボル表
+///
+/// 表の形式は`build.rs`を参照．シンボルを入れなかった場合は空の表になっている
+/// 空でも常に`SYMBOL_TABLE_SIZE`(256KiB)バイトをカーネルイメージに持つ
+static SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] =
+    *include_bytes!(concat!(env!("OUT_DIR"), "/symbols.bin"));
+
+/// アドレスを含む関数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: &'static str,
+    /// 関数の先頭からのオフセット
+    pub offset: u64,
+}
+
+/// 埋め込まれたシンボル表から`addr`を含む関数を探す
+pub fn symbolize(addr: u64) -> Option<Symbol> {
+    // 中身を見て畳み込まれると，空の表と表を入れたときとで関数のアドレスが変わってしまうので，
+    // コンパイラからは中身の分からない表として読む
+    lookup(core::hint::black_box(&SYMBOL_TABLE), addr)
+}
+
+/// `table`から，`addr`以下で最も大きいアドレスのシンボルを探す
+fn lookup(table: &'static [u8], addr: u64) -> Option<Symbol> {
+    let read_u32 = |offset: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(
+            table.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    let read_u64 = |offset: usize| -> Option<u64> {
+        Some(u64::from_le_bytes(
+            table.get(offset..offset + 8)?.try_into().ok()?,
+        ))
+    };
+    if table.get(..4)? != b"KSYM" {
+        return None;
+    }
+    let count = read_u32(4)? as usize;
+    let entry = |index: usize| HEADER_SIZE + index * ENTRY_SIZE;
+
+    // エントリはアドレス順に並んでいるので二分探索する
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if read_u64(entry(mid))? <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let index = low.checked_sub(1)?;
+    let start = read_u64(entry(index))?;
+    let name_offset = read_u32(entry(index) + 8)? as usize;
+    let name_len = read_u32(entry(index) + 12)? as usize;
+    let name = table.get(name_offset..name_offset + name_len)?;
+    Some(Symbol {
+        name: core::str::from_utf8(name).ok()?,
+        offset: addr - start,
+    })
+}
+
+/// フレームポインタをたどって集めたリターンアドレスの列
+///
+/// 例外ハンドラやpanicから使うので，ヒープを使わない固定長の配列に入れる
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    fn new() -> Self {
+        Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        }
+    }
+
+    /// 呼び出し元からのバックトレースを取る
+    #[inline(never)]
+    pub fn capture() -> Self {
+        let mut backtrace = Backtrace::new();
+        backtrace.walk(current_frame_pointer());
+        backtrace
+    }
+
+    /// 例外が起きた命令からのバックトレースを取る
+    ///
+    /// 例外ハンドラの中で，CPUが積んだ`stack_frame`をそのまま渡して呼ぶ
+    /// 例外ハンドラのフレームを探し，そこに保存されている割り込まれたコードのフレームポインタからたどる
+    #[inline(never)]
+    pub fn from_exception(stack_frame: &InterruptStackFrame) -> Self {
+        let mut backtrace = Backtrace::new();
+        backtrace.push(stack_frame.instruction_pointer.as_u64());
+
+        // ハンドラはCPUが積んだフレーム(とエラーコード)の直下にフレームポインタを積む
+        let frame_address = stack_frame as *const InterruptStackFrame as u64;
+        let handler_frame_range = frame_address - 16..frame_address;
+        let mut rbp = current_frame_pointer();
+        for _ in 0..MAX_FRAMES {
+            if handler_frame_range.contains(&rbp) {
+                if let Ok(interrupted) = unsafe { read_checked(rbp as *const u64) } {
+                    backtrace.walk(interrupted);
+                }
+                break;
+            }
+            match unsafe { read_checked(rbp as *const u64) } {
+                Ok(next) if next > rbp => rbp = next,
+                _ => break,
+            }
+        }
+        backtrace
+    }
+
+    /// 記録したアドレス．最初のものが最も内側になる
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+
+    fn push(&mut self, addr: u64) {
+        if self.len < MAX_FRAMES {
+            self.frames[self.len] = addr;
+            self.len += 1;
+        }
+    }
+
+    /// フレームポインタ`rbp`から呼び出し元へ順にたどり，リターンアドレスを記録する
+    ///
+    /// 壊れたスタックをたどっても停止しないよう，メモリは`read_checked`で読む
+    fn walk(&mut self, mut rbp: u64) {
+        while self.len < MAX_FRAMES && rbp != 0 && rbp & 7 == 0 {
+            let [next, return_address] = match unsafe { read_checked(rbp as *const [u64; 2]) } {
+                Ok(frame) => frame,
+                Err(_) => break,
+            };
+            if return_address == 0 {
+                break;
+            }
+            self.push(return_address);
+            // スタックは下に伸びるので，呼び出し元のフレームは必ず上にある
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Backtrace:")?;
+        for (i, &addr) in self.frames().iter().enumerate() {
+            write!(f, "\n  #{:<2} {:#018x}", i, addr)?;
+            // リターンアドレスは呼び出し命令の次を指すので，1つ前のアドレスで探す
+            if let Some(symbol) = symbolize(addr.saturating_sub(1)) {
+                write!(f, " {}+{:#x}", symbol.name, symbol.offset + 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 現在の関数のフレームポインタ
+///
+/// カーネルはフレームポインタを省略しないようにビルドする(`x86_64-blog_os.json`)
+#[inline(always)]
+fn current_frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn capture_from_callee() -> Backtrace {
+    Backtrace::capture()
+}
+
+#[test_case]
+fn test_capture_starts_at_caller() {
+    let backtrace = capture_from_callee();
+    let callee = capture_from_callee as fn() -> Backtrace as usize as u64;
+    assert!(backtrace.frames().len() >= 2);
+    // 最初のフレームは`capture_from_callee`の中に戻る
+    let first = backtrace.frames()[0];
+    assert!(first > callee && first < callee + 0x100);
+}
+
+#[test_case]
+fn test_lookup_symbol() {
+    static TABLE: [u8; 48] = {
+        let mut table = [0; 48];
+        let header = *b"KSYM\x02\x00\x00\x00";
+        // 0x1000: "foo", 0x2000: "bar"
+        let entries = [0x1000u64, 40 | 3 << 32, 0x2000, 43 | 3 << 32];
+        let names = *b"foobar";
+        let mut i = 0;
+        while i < 8 {
+            table[i] = header[i];
+            i += 1;
+        }
+        let mut i = 0;
+        while i < 32 {
+            table[8 + i] = entries[i / 8].to_le_bytes()[i % 8];
+            i += 1;
+        }
+        let mut i = 0;
+        while i < 6 {
+            table[40 + i] = names[i];
+            i += 1;
+        }
+        table
+    };
+
+    assert_eq!(lookup(&TABLE, 0xfff), None);
+    assert_eq!(
+        lookup(&TABLE, 0x1010),
+        Some(Symbol {
+            name: "foo",
+            offset: 0x10
+        })
+    );
+    assert_eq!(
+        lookup(&TABLE, 0x2345),
+        Some(Symbol {
+            name: "bar",
+            offset: 0x345
+        })
+    );
+}
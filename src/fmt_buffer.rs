@@ -0,0 +1,53 @@
+use core::fmt;
+
+/// `N`バイトの固定長のバッファに書き込む`fmt::Write`
+///
+/// panicハンドラや例外ハンドラのテストなど，ヒープを使えない場所で文字列を組み立てるのに使う
+/// 入りきらない分は捨てる
+pub struct FmtBuffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> FmtBuffer<N> {
+    pub const fn new() -> Self {
+        FmtBuffer {
+            bytes: [0; N],
+            len: 0,
+        }
+    }
+
+    /// 書き込んだ文字列．文字の途中で切り捨てていたら，その文字の前までを返す
+    pub fn as_str(&self) -> &str {
+        let bytes = &self.bytes[..self.len];
+        match core::str::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(err) => core::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or(""),
+        }
+    }
+}
+
+impl<const N: usize> Default for FmtBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for FmtBuffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = (self.len + s.len()).min(N);
+        self.bytes[self.len..end].copy_from_slice(&s.as_bytes()[..end - self.len]);
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_fmt_buffer_truncates() {
+    use core::fmt::Write;
+
+    let mut buffer = FmtBuffer::<8>::new();
+    write!(buffer, "{}-あい", 1234).unwrap();
+    // "1234-あ"で7バイト．"い"は途中で切れるので含めない
+    assert_eq!(buffer.as_str(), "1234-あ");
+}
@@ -1,44 +1,150 @@
+use crate::memory::{self, region};
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::paging::{Mapper, Page, Size4KiB};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+// ISTのエントリ番号
 // 0番目のISTエントリをダブルフォルト用のスタックとして定義
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
+
+// ISTのスタックの大きさ(ページ数)．前後のガードページは含まない
+pub const DOUBLE_FAULT_STACK_PAGES: usize = 5;
+pub const NMI_STACK_PAGES: usize = 4;
+pub const MACHINE_CHECK_STACK_PAGES: usize = 4;
+pub const PAGE_FAULT_STACK_PAGES: usize = 16;
+/// page faultのハンドラの中で起きたpage faultは，このページ数だけ下から積ませる
+/// (`PAGE_FAULT_STACK_PAGES / PAGE_FAULT_NESTING_PAGES`段までネストできる)
+pub const PAGE_FAULT_NESTING_PAGES: usize = 4;
+/// ユーザモードから割り込み・例外でカーネルに入ったときに使うスタックのページ数
+pub const PRIVILEGE_STACK_PAGES: usize = 8;
+
+const PAGE_SIZE: usize = 4096;
+
+/// ISTに登録したスタック
+#[derive(Debug, Clone, Copy)]
+pub struct IstStack {
+    pub name: &'static str,
+    pub index: u16,
+    /// スタックの範囲`[bottom, top)`．前後のページはガードページになる
+    pub bottom: VirtAddr,
+    pub top: VirtAddr,
+}
+
+impl IstStack {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.bottom <= addr && addr < self.top
+    }
+}
+
+/// `$pages`ページのスタックを，前後のガードページと合わせて静的に確保し，
+/// ガードページを除いた範囲`(bottom, top)`を返す
+///
+/// ガードページのマップは`protect_kernel_stacks`で外す
+macro_rules! static_stack {
+    ($pages:expr) => {{
+        const SIZE: usize = PAGE_SIZE * ($pages + 2);
+        #[repr(C, align(4096))]
+        struct Stack([u8; SIZE]);
+        static mut STACK: Stack = Stack([0; SIZE]);
+        // static mutへのaddr_of!はツールチェーンによってはunsafe
+        #[allow(unused_unsafe)]
+        let start = VirtAddr::from_ptr(unsafe { addr_of!(STACK) });
+        (start + PAGE_SIZE, start + SIZE - PAGE_SIZE)
+    }};
+}
+
+macro_rules! ist_stack {
+    ($name:expr, $index:expr, $pages:expr) => {{
+        let (bottom, top) = static_stack!($pages);
+        IstStack {
+            name: $name,
+            index: $index,
+            bottom,
+            top,
+        }
+    }};
+}
 
 lazy_static! {
-    // Task State Segmentという構造体を定義
-    // 2つのStack Tableを持つ
-    // 既知の正常なスタックの場所を定義する
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        // 先頭アドレスを0番目のエントリに書き込む
-        // -> x86のスタックは下に伸びていく仕様であるため
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+    static ref IST_STACKS: [IstStack; 4] = [
+        ist_stack!(
+            "double fault stack",
+            DOUBLE_FAULT_IST_INDEX,
+            DOUBLE_FAULT_STACK_PAGES
+        ),
+        ist_stack!("NMI stack", NMI_IST_INDEX, NMI_STACK_PAGES),
+        ist_stack!(
+            "machine check stack",
+            MACHINE_CHECK_IST_INDEX,
+            MACHINE_CHECK_STACK_PAGES
+        ),
+        ist_stack!(
+            "page fault stack",
+            PAGE_FAULT_IST_INDEX,
+            PAGE_FAULT_STACK_PAGES
+        ),
+    ];
+
+    /// ユーザモードから割り込みで入ったときのスタック(`privilege_stack_table[0]`)
+    static ref PRIVILEGE_STACK: (VirtAddr, VirtAddr) = static_stack!(PRIVILEGE_STACK_PAGES);
 }
 
+// Task State Segmentという構造体を定義
+// 既知の正常なスタックの場所を定義する
+// ISTの先頭をハンドラの実行中にずらすため(`IstShift`)，書き換えられるようにしておく
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
 lazy_static! {
     // Global Descriptor Tableの定義
     // カーネル・ユーザモードの設定やTSSの読み込みなどを行う
     static ref GDT:(GlobalDescriptorTable, Selectors) = {
+        // 各スタックの先頭アドレスをISTのエントリに書き込む
+        // -> x86のスタックは下に伸びていく仕様であるため
+        // GDTにTSSを登録する前なので，まだCPUからは参照されていない
+        let tss = unsafe { &mut *addr_of_mut!(TSS) };
+        for stack in IST_STACKS.iter() {
+            tss.interrupt_stack_table[usize::from(stack.index)] = stack.top;
+        }
+        // ring 3からring 0に移るとき，CPUはここのスタックに切り替える
+        tss.privilege_stack_table[0] = PRIVILEGE_STACK.1;
+
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors{code_selector, tss_selector})
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
+        // SYSRETを使う場合の並びに合わせて，ユーザのデータセグメントをコードセグメントの前に置く
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let selectors = Selectors {
+            code_selector,
+            data_selector,
+            tss_selector,
+            user_code_selector,
+            user_data_selector,
+        };
+        (gdt, selectors)
     };
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+}
+
+/// ユーザモードで使うセグメントセレクタ(RPLは3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserSelectors {
+    pub code: SegmentSelector,
+    pub data: SegmentSelector,
 }
 
 pub fn init() {
@@ -51,11 +157,98 @@ pub fn init() {
     //      TSSセレクタを含むGDTを1.でロードしたが，CPUにもこのTSSを使うように教えてあげる
     // 3. IDTエントリを更新する
     //      TSSがロードされるとCPUは正常なISTへアクセスできるようになる。→ダブルフォルトが起きたときにダブルフォルトIDTエントリを変更してCPUに新しいダブルフォルトスタックを使うように教えてあげることができる
-    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
     use x86_64::instructions::tables::load_tss;
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        // データセグメントもこのGDTのものに読み込み直す
+        SS::set_reg(GDT.1.data_selector);
+        DS::set_reg(GDT.1.data_selector);
+        ES::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// ユーザモードのコード・データセグメントのセレクタ
+pub fn user_selectors() -> UserSelectors {
+    UserSelectors {
+        code: GDT.1.user_code_selector,
+        data: GDT.1.user_data_selector,
+    }
+}
+
+/// ISTに登録したスタックの一覧
+pub fn ist_stacks() -> &'static [IstStack] {
+    &IST_STACKS[..]
+}
+
+/// ISTと`privilege_stack_table`のスタックの前後のガードページのマップを外し，領域として登録する
+///
+/// `memory::init_kernel_memory`から呼ばれる．以降はスタックからはみ出すとページフォルトになり，
+/// `region::guard_page_hit`でどのスタックからはみ出したかがわかる
+pub(crate) fn protect_kernel_stacks() {
+    let (privilege_bottom, privilege_top) = *PRIVILEGE_STACK;
+    let stacks = IST_STACKS
+        .iter()
+        .map(|stack| (stack.name, stack.bottom, stack.top))
+        .chain(core::iter::once((
+            "privilege stack",
+            privilege_bottom,
+            privilege_top,
+        )));
+    for (name, bottom, top) in stacks {
+        let guard_pages: [Page<Size4KiB>; 2] = [
+            Page::containing_address(bottom - 1u64),
+            Page::containing_address(top),
+        ];
+        memory::with_kernel_memory(|kernel_memory| {
+            for page in guard_pages {
+                // ガードページのフレームはカーネルイメージの一部なので解放しない
+                // 外せなければスタックからはみ出しても気づけないので，起動を止める
+                let (_, flush) = kernel_memory
+                    .mapper
+                    .unmap(page)
+                    .expect("failed to unmap kernel stack guard page");
+                flush.flush();
+            }
+        })
+        .expect("kernel memory is not available");
+        region::register(name, bottom, top).expect("failed to register kernel stack");
+    }
+}
+
+/// 例外ハンドラの実行中だけ，ISTのエントリを`pages`ページ下にずらす
+///
+/// ISTを使う例外はいつもスタックの先頭から積まれるので，ハンドラの中で同じ例外が起きると
+/// 実行中のハンドラのスタックを上書きしてしまう．ずらしている間に起きた例外は，
+/// 実行中のハンドラより下から積まれる．dropすると元に戻す
+pub struct IstShift {
+    index: u16,
+    bytes: u64,
+}
+
+impl IstShift {
+    pub fn new(index: u16, pages: usize) -> Self {
+        let shift = IstShift {
+            index,
+            bytes: (pages * PAGE_SIZE) as u64,
+        };
+        shift.update(|top| top - shift.bytes);
+        shift
+    }
+
+    /// ISTのエントリを読み書きする．CPUが直接読むので，volatileにアクセスする
+    fn update(&self, f: impl FnOnce(VirtAddr) -> VirtAddr) {
+        unsafe {
+            let entry = addr_of_mut!(TSS.interrupt_stack_table[usize::from(self.index)]);
+            entry.write_volatile(f(entry.read_volatile()));
+        }
+    }
+}
+
+impl Drop for IstShift {
+    fn drop(&mut self) {
+        self.update(|top| top + self.bytes);
+    }
+}
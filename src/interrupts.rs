@@ -1,13 +1,21 @@
-use crate::gdt;
-use crate::hlt_loop;
+use crate::acpi::{self, ISA_IRQS};
 use crate::print;
 use crate::println;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
-use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
+pub mod apic;
+pub mod exceptions;
+pub mod fixup;
+pub mod ioapic;
+pub mod irq;
+
+pub use apic::ApicError;
+pub use fixup::{register_fixup, search_fixup, unregister_fixup, Fixup, FixupError};
+pub use irq::{register_irq, set_irq_masked, unregister_irq, IrqError, IrqHandler};
+
 // PICの割り込みベクタ番号の更新
 // 32個の例外スロットが既に存在するので，その後から8個
 pub const PIC_1_OFFSET: u8 = 32;
@@ -29,8 +37,9 @@ impl InterruptIndex {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    /// この割り込みのISA IRQ番号
+    fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
     }
 }
 
@@ -39,15 +48,13 @@ impl InterruptIndex {
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        };
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        // CPUの例外は全て捕まえて，原因をクラッシュレポートとして出す
+        exceptions::set_handlers(&mut idt);
+        // IRQはスタブから登録されたハンドラを呼ぶ
+        irq::set_stub_handlers(&mut idt);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+        // ユーザモードからカーネルに戻るための割り込み
+        crate::usermode::set_exit_handler(&mut idt);
         idt
     };
 }
@@ -56,28 +63,66 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// 8259 PICを初期化し，タイマとキーボードのハンドラを登録する
+///
+/// ハンドラが登録されていないIRQはマスクしておく
+pub fn init_irqs() {
+    // PICの設定を間違って設定すると未定義動作になるのでunsafe
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.initialize();
+        // IRQ2はスレーブのPICをつなぐのでマスクしない
+        pics.write_masks(!(1 << 2), 0xff);
+    }
+    register_irq(InterruptIndex::Timer.irq(), timer_interrupt_handler)
+        .expect("timer IRQ is already registered");
+    register_irq(InterruptIndex::Keyboard.irq(), keyboard_interrupt_handler)
+        .expect("keyboard IRQ is already registered");
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+/// Local APICとI/O APICを有効にし，ISA IRQを8259 PICではなくI/O APIC経由で受け取る
+///
+/// I/O APICはACPIのMADTから探し，ISA IRQ `n`を今までと同じベクタ`PIC_1_OFFSET + n`に割り当てる
+/// ハンドラが登録されているIRQ以外はマスクしたままにし，8259 PICは全てマスクする
+/// MADTを読んでレジスタをマップするので，`memory::init_kernel_memory`の後に呼ぶ
+/// 失敗した場合は8259 PICを使い続ける
+pub fn init_apic() -> Result<(), ApicError> {
+    let info = acpi::find_apic_info().map_err(ApicError::Acpi)?;
+    let io_apic_info = info
+        .io_apics()
+        .find(|io_apic| io_apic.gsi_base == 0)
+        .ok_or(ApicError::NoIoApic)?;
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        ioapic::init(io_apic_info)?;
+        // Local APICを有効にした時点から，EOIはLocal APICに送られる
+        let local_apic = apic::init_local_apic(info.local_apic_address)?;
+        for irq in 0..ISA_IRQS as u8 {
+            // IRQ2は2つのPICをつなぐためのもので，割り込みは来ない
+            if irq == 2 {
+                continue;
+            }
+            let route = info.irq_route(irq);
+            ioapic::route_isa_irq(irq, route, PIC_1_OFFSET + irq, local_apic.id());
+        }
+        PICS.lock().disable();
+        for irq in 0..ISA_IRQS as u8 {
+            if irq::irq_handler(irq).is_some() {
+                ioapic::set_irq_masked(irq, false);
+            }
+        }
+        Ok(())
+    })
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn timer_interrupt_handler(_irq: u8) {
     // print!(".");
-    // PICは割り込み終了の信号を待つので，
-    // EOI(End of Interrupt) 信号を送る
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    crate::time::tick();
+    // 期限の来たsleep・timeoutを待っているタスクを起こす
+    crate::task::timer::expire_timers(crate::time::ticks());
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn keyboard_interrupt_handler(_irq: u8) {
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
     use x86_64::instructions::port::Port;
@@ -107,26 +152,12 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     //         }
     //     }
     // }
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
 }
 
-/// page faultが起こったときのハンドラ関数
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
-}
+/// Local APICのスプリアス割り込み
+///
+/// 実際の割り込みではないので，EOIを送ってはならない
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
 
 #[test_case]
 fn test_breakpoint_exception() {
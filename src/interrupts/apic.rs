@@ -0,0 +1,104 @@
+use crate::acpi::AcpiError;
+use crate::memory::region::RegionError;
+use crate::memory::{self, MmioRegion};
+use conquer_once::spin::OnceCell;
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Local APICのレジスタのオフセット
+const REG_ID: usize = 0x20;
+const REG_TASK_PRIORITY: usize = 0x80;
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS: usize = 0xf0;
+
+/// IA32_APIC_BASE MSRと，その中のAPICを有効にするビット
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+/// スプリアス割り込みベクタレジスタの，APICを有効にするビット
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+/// Local APICがスプリアス割り込みに使うベクタ
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// APICの初期化に失敗した理由
+#[derive(Debug)]
+pub enum ApicError {
+    /// CPUがLocal APICを持っていない
+    Unsupported,
+    /// MADTが読めない
+    Acpi(AcpiError),
+    /// MADTにI/O APICが無い
+    NoIoApic,
+    /// レジスタをマップできない
+    Map(RegionError),
+}
+
+/// このCPUのLocal APIC
+#[derive(Debug)]
+pub struct LocalApic {
+    base: VirtAddr,
+    _mmio: MmioRegion,
+}
+
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+
+impl LocalApic {
+    pub fn id(&self) -> u8 {
+        (self.read(REG_ID) >> 24) as u8
+    }
+
+    /// 割り込みの処理が終わったことを伝える
+    pub fn end_of_interrupt(&self) {
+        self.write(REG_EOI, 0);
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + reg).as_ptr()) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base + reg).as_mut_ptr(), value) }
+    }
+}
+
+/// CPUIDでLocal APICがあるかどうかを調べる
+pub fn is_supported() -> bool {
+    // __cpuidはツールチェーンによってはunsafe
+    #[allow(unused_unsafe)]
+    let features = unsafe { __cpuid(1) };
+    features.edx & (1 << 9) != 0
+}
+
+/// `phys`にあるLocal APICのレジスタをマップし，有効にする
+///
+/// この関数はunsafeである：呼び出し元は`phys`がこのCPUのLocal APICのアドレスであることと，
+/// 割り込みが無効になっていることを保証しなければならない
+pub(super) unsafe fn init_local_apic(phys: PhysAddr) -> Result<&'static LocalApic, ApicError> {
+    if !is_supported() {
+        return Err(ApicError::Unsupported);
+    }
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    apic_base.write(apic_base.read() | APIC_GLOBAL_ENABLE);
+
+    let mmio = memory::map_mmio(phys, 4096).map_err(ApicError::Map)?;
+    let apic = LocalApic {
+        base: mmio.base(),
+        _mmio: mmio,
+    };
+    // 全ての優先度の割り込みを受け付け，スプリアス割り込みのベクタを決めてAPICを有効にする
+    apic.write(REG_TASK_PRIORITY, 0);
+    apic.write(
+        REG_SPURIOUS,
+        SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR),
+    );
+    LOCAL_APIC.try_init_once(|| apic).ok();
+    Ok(LOCAL_APIC.get().unwrap())
+}
+
+/// 有効にしたLocal APIC(`init_apic`の前は`None`)
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.get()
+}
@@ -0,0 +1,608 @@
+use super::fixup;
+use crate::backtrace::Backtrace;
+use crate::gdt;
+use crate::println;
+use core::fmt;
+#[cfg(test)]
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+#[cfg(test)]
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+#[cfg(test)]
+use x86_64::VirtAddr;
+
+// 例外のベクタ番号
+pub const DIVIDE_ERROR: u8 = 0;
+pub const DEBUG: u8 = 1;
+pub const NON_MASKABLE_INTERRUPT: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const OVERFLOW: u8 = 4;
+pub const BOUND_RANGE_EXCEEDED: u8 = 5;
+pub const INVALID_OPCODE: u8 = 6;
+pub const DEVICE_NOT_AVAILABLE: u8 = 7;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_SEGMENT_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION_FAULT: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const X87_FLOATING_POINT: u8 = 16;
+pub const ALIGNMENT_CHECK: u8 = 17;
+pub const MACHINE_CHECK: u8 = 18;
+pub const SIMD_FLOATING_POINT: u8 = 19;
+pub const VIRTUALIZATION: u8 = 20;
+pub const VMM_COMMUNICATION: u8 = 29;
+pub const SECURITY: u8 = 30;
+
+/// 例外のニーモニック(`#GP`など)と名前を返す
+pub fn exception_name(vector: u8) -> Option<(&'static str, &'static str)> {
+    let name = match vector {
+        DIVIDE_ERROR => ("#DE", "DIVIDE ERROR"),
+        DEBUG => ("#DB", "DEBUG"),
+        NON_MASKABLE_INTERRUPT => ("NMI", "NON-MASKABLE INTERRUPT"),
+        BREAKPOINT => ("#BP", "BREAKPOINT"),
+        OVERFLOW => ("#OF", "OVERFLOW"),
+        BOUND_RANGE_EXCEEDED => ("#BR", "BOUND RANGE EXCEEDED"),
+        INVALID_OPCODE => ("#UD", "INVALID OPCODE"),
+        DEVICE_NOT_AVAILABLE => ("#NM", "DEVICE NOT AVAILABLE"),
+        DOUBLE_FAULT => ("#DF", "DOUBLE FAULT"),
+        INVALID_TSS => ("#TS", "INVALID TSS"),
+        SEGMENT_NOT_PRESENT => ("#NP", "SEGMENT NOT PRESENT"),
+        STACK_SEGMENT_FAULT => ("#SS", "STACK-SEGMENT FAULT"),
+        GENERAL_PROTECTION_FAULT => ("#GP", "GENERAL PROTECTION FAULT"),
+        PAGE_FAULT => ("#PF", "PAGE FAULT"),
+        X87_FLOATING_POINT => ("#MF", "X87 FLOATING-POINT EXCEPTION"),
+        ALIGNMENT_CHECK => ("#AC", "ALIGNMENT CHECK"),
+        MACHINE_CHECK => ("#MC", "MACHINE CHECK"),
+        SIMD_FLOATING_POINT => ("#XM", "SIMD FLOATING-POINT EXCEPTION"),
+        VIRTUALIZATION => ("#VE", "VIRTUALIZATION EXCEPTION"),
+        VMM_COMMUNICATION => ("#VC", "VMM COMMUNICATION EXCEPTION"),
+        SECURITY => ("#SX", "SECURITY EXCEPTION"),
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// セレクタのエラーコードが指しているディスクリプタテーブル
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// #TS, #NP, #SS, #GPのエラーコード
+///
+/// 例外の原因になったセグメントセレクタ(またはIDTのエントリ)を表す
+/// 0のときはセレクタと関係なく起きた例外である
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+    /// プログラムの外(外部割り込みなど)が原因で起きたかどうか
+    pub fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    /// テーブル内のインデックス．IDTの場合はベクタ番号になる
+    pub fn index(self) -> u16 {
+        ((self.0 >> 3) & 0x1fff) as u16
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "{:#x} (no selector)", self.0);
+        }
+        write!(
+            f,
+            "{:#x} ({:?} index {}",
+            self.0,
+            self.table(),
+            self.index()
+        )?;
+        if self.external() {
+            write!(f, ", external")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// 例外が起きたときの状態をまとめたもの
+///
+/// ベクタの名前，エラーコードの意味，割り込みスタックフレームと制御レジスタを表示する
+pub struct CrashReport<'a> {
+    vector: u8,
+    error_code: Option<u64>,
+    stack_frame: &'a InterruptStackFrame,
+}
+
+impl<'a> CrashReport<'a> {
+    pub fn new(vector: u8, error_code: Option<u64>, stack_frame: &'a InterruptStackFrame) -> Self {
+        CrashReport {
+            vector,
+            error_code,
+            stack_frame,
+        }
+    }
+}
+
+impl fmt::Display for CrashReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match exception_name(self.vector) {
+            Some((mnemonic, name)) => writeln!(
+                f,
+                "EXCEPTION: {} ({}, vector {})",
+                name, mnemonic, self.vector
+            )?,
+            None => writeln!(f, "EXCEPTION: vector {}", self.vector)?,
+        }
+        if let Some(code) = self.error_code {
+            match self.vector {
+                INVALID_TSS
+                | SEGMENT_NOT_PRESENT
+                | STACK_SEGMENT_FAULT
+                | GENERAL_PROTECTION_FAULT => {
+                    writeln!(f, "Error Code: {}", SelectorErrorCode(code))?
+                }
+                PAGE_FAULT => writeln!(
+                    f,
+                    "Error Code: {:#x} ({:?})",
+                    code,
+                    PageFaultErrorCode::from_bits_truncate(code)
+                )?,
+                _ => writeln!(f, "Error Code: {:#x}", code)?,
+            }
+        }
+        writeln!(f, "{:#?}", self.stack_frame)?;
+        let (level_4_table, _) = Cr3::read();
+        writeln!(f, "CR0: {:?}", Cr0::read())?;
+        writeln!(f, "CR2: {:?}", Cr2::read())?;
+        writeln!(f, "CR3: {:?}", level_4_table.start_address())?;
+        write!(f, "CR4: {:?}", Cr4::read())
+    }
+}
+
+/// 例外が期待されていないことを表す値
+#[cfg(test)]
+const NO_EXCEPTION: u16 = u16::MAX;
+
+/// テストで意図的に起こしている例外のベクタ
+#[cfg(test)]
+static EXPECTED_VECTOR: AtomicU16 = AtomicU16::new(NO_EXCEPTION);
+/// 期待した例外が起きたときに実行を再開するアドレス
+#[cfg(test)]
+static RESUME_ADDRESS: AtomicU64 = AtomicU64::new(0);
+/// 期待した例外が起きたかどうかと，そのエラーコード
+#[cfg(test)]
+static CAUGHT: AtomicBool = AtomicBool::new(false);
+#[cfg(test)]
+static CAUGHT_ERROR_CODE: AtomicU64 = AtomicU64::new(0);
+
+/// `vector`の例外が期待されていたら，記録して`RESUME_ADDRESS`から再開させる
+#[cfg(test)]
+fn recover_expected(vector: u8, stack_frame: &mut InterruptStackFrame, error_code: u64) -> bool {
+    if EXPECTED_VECTOR.load(Ordering::SeqCst) != u16::from(vector) {
+        return false;
+    }
+    EXPECTED_VECTOR.store(NO_EXCEPTION, Ordering::SeqCst);
+    CAUGHT_ERROR_CODE.store(error_code, Ordering::SeqCst);
+    CAUGHT.store(true, Ordering::SeqCst);
+
+    let resume = VirtAddr::new(RESUME_ADDRESS.load(Ordering::SeqCst));
+    // シングルステップで起こした#DBが続けて起きないよう，TFも落としておく
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = resume;
+            frame.cpu_flags &= !RFlags::TRAP_FLAG.bits();
+        });
+    }
+    true
+}
+
+/// 全ての例外のハンドラをIDTに登録する
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    // NMI，マシンチェック，page faultはスタックが壊れていても起こりうるので，
+    // ダブルフォルトと同じように専用のスタックで処理する
+    unsafe {
+        idt.non_maskable_interrupt
+            .set_handler_fn(non_maskable_interrupt_handler)
+            .set_stack_index(gdt::NMI_IST_INDEX);
+    }
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    };
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    unsafe {
+        idt.page_fault
+            .set_handler_fn(page_fault_handler)
+            .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+    }
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    unsafe {
+        idt.machine_check
+            .set_handler_fn(machine_check_handler)
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    }
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.vmm_communication_exception
+        .set_handler_fn(vmm_communication_handler);
+    idt.security_exception.set_handler_fn(security_handler);
+}
+
+/// エラーコードのない例外のハンドラを作る
+///
+/// 期待された例外でなければ，クラッシュレポートを出してpanicする
+/// (例外を期待するのはテストだけなので，テスト以外では`stack_frame`を書き換えない)
+macro_rules! exception_handlers {
+    ($($handler:ident => $vector:expr),* $(,)?) => {
+        $(
+            #[cfg_attr(not(test), allow(unused_mut))]
+            extern "x86-interrupt" fn $handler(mut stack_frame: InterruptStackFrame) {
+                #[cfg(test)]
+                if recover_expected($vector, &mut stack_frame, 0) {
+                    return;
+                }
+                panic!("{}", CrashReport::new($vector, None, &stack_frame));
+            }
+        )*
+    };
+}
+
+/// エラーコードを持つ例外のハンドラを作る
+macro_rules! exception_handlers_with_error_code {
+    ($($handler:ident => $vector:expr),* $(,)?) => {
+        $(
+            #[cfg_attr(not(test), allow(unused_mut))]
+            extern "x86-interrupt" fn $handler(mut stack_frame: InterruptStackFrame, error_code: u64) {
+                #[cfg(test)]
+                if recover_expected($vector, &mut stack_frame, error_code) {
+                    return;
+                }
+                panic!("{}", CrashReport::new($vector, Some(error_code), &stack_frame));
+            }
+        )*
+    };
+}
+
+exception_handlers! {
+    divide_error_handler => DIVIDE_ERROR,
+    debug_handler => DEBUG,
+    non_maskable_interrupt_handler => NON_MASKABLE_INTERRUPT,
+    overflow_handler => OVERFLOW,
+    bound_range_exceeded_handler => BOUND_RANGE_EXCEEDED,
+    invalid_opcode_handler => INVALID_OPCODE,
+    device_not_available_handler => DEVICE_NOT_AVAILABLE,
+    x87_floating_point_handler => X87_FLOATING_POINT,
+    simd_floating_point_handler => SIMD_FLOATING_POINT,
+    virtualization_handler => VIRTUALIZATION,
+}
+
+exception_handlers_with_error_code! {
+    invalid_tss_handler => INVALID_TSS,
+    segment_not_present_handler => SEGMENT_NOT_PRESENT,
+    stack_segment_fault_handler => STACK_SEGMENT_FAULT,
+    alignment_check_handler => ALIGNMENT_CHECK,
+    vmm_communication_handler => VMM_COMMUNICATION,
+    security_handler => SECURITY,
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    panic!(
+        "{}\n{}",
+        CrashReport::new(DOUBLE_FAULT, Some(error_code), &stack_frame),
+        Backtrace::from_exception(&stack_frame)
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    // 非canonicalなアドレスへのアクセスはpage faultではなく#GPになる
+    if fixup::apply_fixup(&mut stack_frame) {
+        return;
+    }
+    #[cfg(test)]
+    if recover_expected(GENERAL_PROTECTION_FAULT, &mut stack_frame, error_code) {
+        return;
+    }
+    panic!(
+        "{}",
+        CrashReport::new(GENERAL_PROTECTION_FAULT, Some(error_code), &stack_frame)
+    );
+}
+
+/// マシンチェックからは復帰できない
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    panic!("{}", CrashReport::new(MACHINE_CHECK, None, &stack_frame));
+}
+
+/// page faultが起こったときのハンドラ関数
+extern "x86-interrupt" fn page_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    // 遅延割り当てやfixupのコピーなど，このハンドラの中でもpage faultは起こる
+    let _shift = gdt::IstShift::new(gdt::PAGE_FAULT_IST_INDEX, gdt::PAGE_FAULT_NESTING_PAGES);
+    let accessed = Cr2::read();
+    // 遅延割り当ての領域などでフレームをマップできたら，そのまま実行を再開する
+    if crate::memory::handle_page_fault(accessed, error_code) {
+        return;
+    }
+    // 例外表に登録されたコピー関数などで起きた場合は，エラーを返すコードへ飛ばす
+    if fixup::apply_fixup(&mut stack_frame) {
+        return;
+    }
+    #[cfg(test)]
+    if recover_expected(PAGE_FAULT, &mut stack_frame, error_code.bits()) {
+        return;
+    }
+
+    let report = CrashReport::new(PAGE_FAULT, Some(error_code.bits()), &stack_frame);
+    let backtrace = Backtrace::from_exception(&stack_frame);
+    // 登録された領域のガードページに触れた場合は，どの領域からはみ出したかを報告する
+    match crate::memory::region::guard_page_hit(accessed) {
+        Some(hit) => panic!("{}\n{}\n{}", hit, report, backtrace),
+        None => panic!("{}\n{}", report, backtrace),
+    }
+}
+
+/// `$vector`の例外が起きることを期待して，`$instruction`を実行する
+///
+/// 例外が起きたらエラーコード(ないものは0)を`Some`で返す
+/// ハンドラは`$instruction`の直後から実行を再開させる
+#[cfg(test)]
+macro_rules! trigger_exception {
+    ($vector:expr, [$($instruction:literal),+ $(,)?] $(, $($operand:tt)*)?) => {{
+        CAUGHT.store(false, Ordering::SeqCst);
+        EXPECTED_VECTOR.store(u16::from($vector), Ordering::SeqCst);
+        unsafe {
+            core::arch::asm!(
+                "lea {resume}, [rip + 2f]",
+                "mov [{resume_address}], {resume}",
+                $($instruction,)+
+                "2:",
+                resume = out(reg) _,
+                resume_address = in(reg) &RESUME_ADDRESS as *const AtomicU64,
+                $($($operand)*)?
+            );
+        }
+        EXPECTED_VECTOR.store(NO_EXCEPTION, Ordering::SeqCst);
+        if CAUGHT.load(Ordering::SeqCst) {
+            Some(CAUGHT_ERROR_CODE.load(Ordering::SeqCst))
+        } else {
+            None
+        }
+    }};
+}
+
+// #TSはTSSを壊さないと起こせず，#BRと#OFを起こすBOUND・INTO命令は64ビットモードでは#UDになるので試さない
+// #ACはring 3でしか起きないので，tests/alignment_check.rsで試す
+
+#[test_case]
+fn test_divide_error() {
+    let caught = trigger_exception!(
+        DIVIDE_ERROR,
+        ["div ecx"],
+        in("ecx") 0,
+        inout("eax") 1 => _,
+        inout("edx") 0 => _,
+    );
+    assert_eq!(caught, Some(0));
+}
+
+#[test_case]
+fn test_debug_single_step() {
+    let caught = trigger_exception!(
+        DEBUG,
+        ["pushfq", "or qword ptr [rsp], 0x100", "popfq", "nop"]
+    );
+    assert_eq!(caught, Some(0));
+    assert!(!x86_64::registers::rflags::read().contains(RFlags::TRAP_FLAG));
+}
+
+#[test_case]
+fn test_invalid_opcode() {
+    assert_eq!(trigger_exception!(INVALID_OPCODE, ["ud2"]), Some(0));
+}
+
+#[test_case]
+fn test_device_not_available() {
+    use x86_64::registers::control::Cr0Flags;
+
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED)) };
+    let caught = trigger_exception!(DEVICE_NOT_AVAILABLE, ["fninit"]);
+    unsafe { Cr0::update(|flags| flags.remove(Cr0Flags::TASK_SWITCHED)) };
+    assert_eq!(caught, Some(0));
+}
+
+#[test_case]
+fn test_x87_floating_point() {
+    use x86_64::registers::control::Cr0Flags;
+
+    // NEが立っていないと，x87の例外は#MFではなく外部割り込み(IRQ 13)で知らされる
+    let cr0 = Cr0::read();
+    unsafe { Cr0::write(cr0 | Cr0Flags::NUMERIC_ERROR) };
+    // ゼロ除算の例外だけマスクを外した制御ワード
+    let control_word: u16 = 0x037b;
+    // 例外はfdivでは起きず，次のfwaitで知らされる
+    let caught = trigger_exception!(
+        X87_FLOATING_POINT,
+        [
+            "fninit",
+            "fldcw word ptr [{control_word}]",
+            "fldz",
+            "fld1",
+            "fdiv st, st(1)",
+            "fwait"
+        ],
+        control_word = in(reg) &control_word,
+    );
+    unsafe {
+        core::arch::asm!("fnclex", "fninit");
+        Cr0::write(cr0);
+    }
+    assert_eq!(caught, Some(0));
+}
+
+#[test_case]
+fn test_simd_floating_point() {
+    use x86_64::registers::control::Cr4Flags;
+
+    // カーネルはSSEを使わない(soft-float)ので，xmmレジスタは壊してよい
+    // この間だけSSEと，マスクを外した例外の#XMでの通知を有効にする
+    let cr4 = Cr4::read();
+    unsafe { Cr4::write(cr4 | Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE) };
+    // ゼロ除算の例外だけマスクを外したMXCSRと，初期値のMXCSR
+    let mxcsr: u32 = 0x1d80;
+    let default_mxcsr: u32 = 0x1f80;
+    let caught = trigger_exception!(
+        SIMD_FLOATING_POINT,
+        [
+            "ldmxcsr dword ptr [{mxcsr}]",
+            "cvtsi2ss xmm1, {one:e}",
+            "xorps xmm0, xmm0",
+            "divss xmm1, xmm0"
+        ],
+        mxcsr = in(reg) &mxcsr,
+        one = in(reg) 1,
+    );
+    unsafe {
+        core::arch::asm!("ldmxcsr dword ptr [{}]", in(reg) &default_mxcsr);
+        Cr4::write(cr4);
+    }
+    assert_eq!(caught, Some(0));
+}
+
+#[test_case]
+fn test_segment_not_present() {
+    // 何も登録していないIDTのエントリへのソフトウェア割り込み
+    let caught = trigger_exception!(SEGMENT_NOT_PRESENT, ["int 0x50"]).unwrap();
+    let code = SelectorErrorCode(caught);
+    assert_eq!(code.table(), DescriptorTable::Idt);
+    assert_eq!(code.index(), 0x50);
+    assert!(!code.external());
+}
+
+#[test_case]
+fn test_stack_segment_fault() {
+    // RSPをベースにした非canonicalなアドレスへのアクセス
+    let caught = trigger_exception!(
+        STACK_SEGMENT_FAULT,
+        ["mov {tmp}, qword ptr [rsp + {offset}]"],
+        tmp = out(reg) _,
+        offset = in(reg) 0x8000_0000_0000_0000u64,
+    );
+    assert_eq!(caught, Some(0));
+}
+
+#[test_case]
+fn test_general_protection_fault_selector() {
+    // GDTの範囲外のセレクタをDSに読み込もうとする
+    let caught = trigger_exception!(
+        GENERAL_PROTECTION_FAULT,
+        ["mov ds, {selector:x}"],
+        selector = in(reg) 0xfff8u16,
+    )
+    .unwrap();
+    let code = SelectorErrorCode(caught);
+    assert_eq!(code.table(), DescriptorTable::Gdt);
+    assert_eq!(code.index(), 0x1fff);
+}
+
+#[test_case]
+fn test_general_protection_fault_non_canonical() {
+    let caught = trigger_exception!(
+        GENERAL_PROTECTION_FAULT,
+        ["mov {tmp}, qword ptr [{addr}]"],
+        tmp = out(reg) _,
+        addr = in(reg) 0x8000_0000_0000_0000u64,
+    );
+    assert_eq!(caught, Some(0));
+}
+
+#[test_case]
+fn test_page_fault() {
+    let caught = trigger_exception!(
+        PAGE_FAULT,
+        ["mov {tmp}, qword ptr [{addr}]"],
+        tmp = out(reg) _,
+        addr = in(reg) 0xdeadbeaf000u64,
+    )
+    .unwrap();
+    let code = PageFaultErrorCode::from_bits_truncate(caught);
+    assert!(!code.contains(PageFaultErrorCode::PROTECTION_VIOLATION));
+    assert!(!code.contains(PageFaultErrorCode::CAUSED_BY_WRITE));
+}
+
+#[test_case]
+fn test_selector_error_code_decoding() {
+    let code = SelectorErrorCode(0x11);
+    assert!(code.external());
+    assert_eq!(code.table(), DescriptorTable::Gdt);
+    assert_eq!(code.index(), 2);
+    assert_eq!(SelectorErrorCode(0x1c).table(), DescriptorTable::Ldt);
+    assert_eq!(SelectorErrorCode(0x1c).index(), 3);
+}
+
+#[test_case]
+fn test_crash_report_format() {
+    use crate::fmt_buffer::FmtBuffer;
+    use core::fmt::Write;
+    use x86_64::structures::idt::InterruptStackFrameValue;
+
+    let value = InterruptStackFrameValue {
+        instruction_pointer: VirtAddr::new(0x1000),
+        code_segment: 8,
+        cpu_flags: 0x2,
+        stack_pointer: VirtAddr::new(0x2000),
+        stack_segment: 0,
+    };
+    // InterruptStackFrameは値をrepr(C)で包んでいるだけなので，そのまま読み替える
+    let frame: InterruptStackFrame = unsafe { core::mem::transmute(value) };
+    let mut buffer = FmtBuffer::<1024>::new();
+    let report = CrashReport::new(GENERAL_PROTECTION_FAULT, Some(0x10), &frame);
+    write!(buffer, "{}", report).unwrap();
+    let report = buffer.as_str();
+    assert!(report.contains("GENERAL PROTECTION FAULT (#GP, vector 13)"));
+    assert!(report.contains("Error Code: 0x10 (Gdt index 2)"));
+}
@@ -0,0 +1,105 @@
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// 登録できる範囲の最大数
+const MAX_FIXUPS: usize = 32;
+
+/// 例外を起こしてよい命令の範囲と，そこで例外が起きたときに飛ぶ先
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fixup {
+    /// 範囲`[start, end)`の命令でpage faultや#GPが起きたら，`fixup`から実行を再開する
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub fixup: VirtAddr,
+}
+
+impl Fixup {
+    fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+/// 範囲の登録に失敗した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixupError {
+    /// 表に空きがない
+    TableFull,
+    /// 空の範囲
+    EmptyRange,
+    /// すでに登録されている範囲と重なっている
+    Overlapping,
+}
+
+/// 例外表
+///
+/// 例外ハンドラから引くので，ヒープを使わない固定長の配列にする
+static FIXUPS: Mutex<[Option<Fixup>; MAX_FIXUPS]> = Mutex::new([None; MAX_FIXUPS]);
+
+/// 命令の範囲`[start, end)`を例外表に登録する
+///
+/// 範囲内の命令でpage fault(遅延割り当てなどで解決できないもの)や#GPが起きると，
+/// 例外ハンドラは停止する代わりに`fixup`から実行を再開させる
+/// `fixup`は呼び出し元にエラーを返すコードでなければならない
+pub fn register_fixup(start: VirtAddr, end: VirtAddr, fixup: VirtAddr) -> Result<(), FixupError> {
+    if start >= end {
+        return Err(FixupError::EmptyRange);
+    }
+    let new = Fixup { start, end, fixup };
+    // 例外ハンドラがロック中の表を読まないよう，割り込みを止めておく
+    interrupts::without_interrupts(|| {
+        let mut fixups = FIXUPS.lock();
+        if fixups
+            .iter()
+            .flatten()
+            .any(|entry| entry.start < end && start < entry.end)
+        {
+            return Err(FixupError::Overlapping);
+        }
+        let slot = fixups
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(FixupError::TableFull)?;
+        *slot = Some(new);
+        Ok(())
+    })
+}
+
+/// `start`から始まる範囲を例外表から外し，外した範囲を返す
+pub fn unregister_fixup(start: VirtAddr) -> Option<Fixup> {
+    interrupts::without_interrupts(|| {
+        FIXUPS
+            .lock()
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(entry) if entry.start == start))?
+            .take()
+    })
+}
+
+/// `addr`の命令を含む範囲を例外表から探す
+///
+/// 例外ハンドラから呼ばれるので，ロックが取れなければ見つからなかったことにする
+pub fn search_fixup(addr: VirtAddr) -> Option<Fixup> {
+    let fixups = FIXUPS.try_lock()?;
+    let found = fixups
+        .iter()
+        .flatten()
+        .find(|entry| entry.contains(addr))
+        .copied();
+    found
+}
+
+/// 例外を起こした命令が例外表にあれば，スタックフレームの命令ポインタを`fixup`に書き換える
+pub(super) fn apply_fixup(stack_frame: &mut InterruptStackFrame) -> bool {
+    let fixup = match search_fixup(stack_frame.instruction_pointer) {
+        Some(fixup) => fixup,
+        None => return false,
+    };
+    unsafe {
+        stack_frame
+            .as_mut()
+            .update(|frame| frame.instruction_pointer = fixup.fixup);
+    }
+    true
+}
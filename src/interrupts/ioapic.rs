@@ -0,0 +1,156 @@
+use super::apic::ApicError;
+use crate::acpi::{IoApicInfo, IrqRoute, ISA_IRQS};
+use crate::memory::{self, MmioRegion};
+use core::ptr;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+/// レジスタ番号を書き込むレジスタと，値を読み書きするレジスタのオフセット
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+/// I/O APICの内部レジスタ
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+/// リダイレクションエントリのビット
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+
+/// I/O APIC
+///
+/// 外部からの割り込み(GSI)を，リダイレクションテーブルに従って
+/// Local APICの割り込みベクタに振り分ける
+#[derive(Debug)]
+pub struct IoApic {
+    base: VirtAddr,
+    _mmio: MmioRegion,
+    gsi_base: u32,
+    /// 受け持つ入力(リダイレクションエントリ)の数
+    inputs: u32,
+    /// ISA IRQごとの，届く先のGSI
+    isa_gsis: [Option<u32>; ISA_IRQS],
+}
+
+/// I/O APICの設定
+///
+/// ここではGSI 0から始まる(ISA IRQを受け持つ)1つだけを扱う
+static IO_APIC: Mutex<Option<IoApic>> = Mutex::new(None);
+
+impl IoApic {
+    /// `info`のI/O APICのレジスタをマップし，全ての入力をマスクする
+    ///
+    /// この関数はunsafeである：呼び出し元は`info`が正しいI/O APICを指していることを
+    /// 保証しなければならない
+    unsafe fn new(info: &IoApicInfo) -> Result<Self, ApicError> {
+        let mmio = memory::map_mmio(info.address, 0x20).map_err(ApicError::Map)?;
+        let mut io_apic = IoApic {
+            base: mmio.base(),
+            _mmio: mmio,
+            gsi_base: info.gsi_base,
+            inputs: 0,
+            isa_gsis: [None; ISA_IRQS],
+        };
+        io_apic.inputs = ((io_apic.read(REG_VERSION) >> 16) & 0xff) + 1;
+        for input in 0..io_apic.inputs {
+            io_apic.write_redirection(input, MASKED);
+        }
+        Ok(io_apic)
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.inputs
+    }
+
+    fn read(&mut self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr(), reg);
+            ptr::read_volatile((self.base + IOWIN).as_ptr())
+        }
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr(), reg);
+            ptr::write_volatile((self.base + IOWIN).as_mut_ptr(), value);
+        }
+    }
+
+    fn read_redirection(&mut self, input: u32) -> u64 {
+        let low = self.read(REG_REDIRECTION_TABLE + input * 2);
+        let high = self.read(REG_REDIRECTION_TABLE + input * 2 + 1);
+        u64::from(high) << 32 | u64::from(low)
+    }
+
+    fn write_redirection(&mut self, input: u32, entry: u64) {
+        // 書き換え中に割り込みが届かないよう，マスクのビットがある下位から書く
+        self.write(REG_REDIRECTION_TABLE + input * 2, MASKED as u32);
+        self.write(REG_REDIRECTION_TABLE + input * 2 + 1, (entry >> 32) as u32);
+        self.write(REG_REDIRECTION_TABLE + input * 2, entry as u32);
+    }
+}
+
+/// `info`のI/O APICを初期化し，以降の`route`・`set_masked`で使う
+///
+/// この関数はunsafeである：`IoApic::new`と同じ
+pub(super) unsafe fn init(info: &IoApicInfo) -> Result<(), ApicError> {
+    let io_apic = IoApic::new(info)?;
+    interrupts::without_interrupts(|| *IO_APIC.lock() = Some(io_apic));
+    Ok(())
+}
+
+/// ISA IRQ `irq`をLocal APIC `apic_id`の`vector`に届くように設定する(マスクしたまま)
+pub(super) fn route_isa_irq(irq: u8, route: IrqRoute, vector: u8, apic_id: u8) {
+    let mut entry = MASKED | u64::from(vector) | u64::from(apic_id) << 56;
+    if route.active_low {
+        entry |= ACTIVE_LOW;
+    }
+    if route.level_triggered {
+        entry |= LEVEL_TRIGGERED;
+    }
+    with_io_apic(|io_apic| {
+        if io_apic.handles(route.gsi) {
+            io_apic.write_redirection(route.gsi - io_apic.gsi_base, entry);
+            io_apic.isa_gsis[usize::from(irq)] = Some(route.gsi);
+        }
+    });
+}
+
+/// ISA IRQ `irq`をマスクする，またはマスクを外す
+///
+/// I/O APICが初期化されていないか，`irq`の経路が設定されていなければ`false`を返す
+pub fn set_irq_masked(irq: u8, masked: bool) -> bool {
+    with_isa_input(irq, |io_apic, input| {
+        let entry = io_apic.read_redirection(input);
+        let entry = if masked {
+            entry | MASKED
+        } else {
+            entry & !MASKED
+        };
+        io_apic.write_redirection(input, entry);
+    })
+    .is_some()
+}
+
+/// ISA IRQ `irq`がマスクされているか(経路が設定されていなければ`None`)
+pub fn is_irq_masked(irq: u8) -> Option<bool> {
+    with_isa_input(irq, |io_apic, input| {
+        io_apic.read_redirection(input) & MASKED != 0
+    })
+}
+
+/// ISA IRQ `irq`が届くI/O APICの入力番号と共に`f`を呼ぶ
+fn with_isa_input<R>(irq: u8, f: impl FnOnce(&mut IoApic, u32) -> R) -> Option<R> {
+    with_io_apic(|io_apic| {
+        let gsi = (*io_apic.isa_gsis.get(usize::from(irq))?)?;
+        let input = gsi - io_apic.gsi_base;
+        Some(f(io_apic, input))
+    })
+    .flatten()
+}
+
+fn with_io_apic<R>(f: impl FnOnce(&mut IoApic) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| IO_APIC.lock().as_mut().map(f))
+}
@@ -0,0 +1,172 @@
+use super::{apic, ioapic, PICS, PIC_1_OFFSET};
+use crate::acpi::ISA_IRQS;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+/// IRQのハンドラ．引数は割り込んだIRQ番号
+///
+/// 割り込みコンテキストで呼ばれるので，割り込みを待つロックを取ってはならない
+/// EOIは呼び出し元がまとめて送る
+pub type IrqHandler = fn(u8);
+
+/// 未登録を表す値
+const NO_HANDLER: usize = 0;
+
+/// IRQごとに登録されているハンドラ(関数ポインタの値)
+///
+/// 割り込みハンドラから読むので，ロックを使わずにアトミックに入れ替える
+#[allow(clippy::declare_interior_mutable_const)]
+static HANDLERS: [AtomicUsize; ISA_IRQS] = {
+    const EMPTY: AtomicUsize = AtomicUsize::new(NO_HANDLER);
+    [EMPTY; ISA_IRQS]
+};
+
+/// ハンドラの登録・解除に失敗した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// 存在しないIRQ番号か，スレーブのPICをつなぐIRQ 2
+    InvalidIrq(u8),
+    /// すでにハンドラが登録されている
+    AlreadyRegistered(u8),
+    /// ハンドラが登録されていない
+    NotRegistered(u8),
+}
+
+/// ハンドラを登録できるIRQなら，その登録先を返す
+///
+/// IRQ 2は8259 PICではスレーブのPICをつなぐ線なので，マスクするとスレーブのIRQが全て止まる
+fn handler_slot(irq: u8) -> Result<&'static AtomicUsize, IrqError> {
+    match HANDLERS.get(usize::from(irq)) {
+        Some(slot) if irq != PIC_CASCADE_IRQ => Ok(slot),
+        _ => Err(IrqError::InvalidIrq(irq)),
+    }
+}
+
+/// IRQ `irq`のハンドラを登録し，そのIRQのマスクを外す
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let slot = handler_slot(irq)?;
+    slot.compare_exchange(
+        NO_HANDLER,
+        handler as usize,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    )
+    .map_err(|_| IrqError::AlreadyRegistered(irq))?;
+    set_irq_masked(irq, false);
+    Ok(())
+}
+
+/// 登録されていたハンドラを外して返し，IRQ `irq`をマスクする
+///
+/// 失敗したときはマスクを変えない
+pub fn unregister_irq(irq: u8) -> Result<IrqHandler, IrqError> {
+    let handler = match handler_slot(irq)?.swap(NO_HANDLER, Ordering::AcqRel) {
+        NO_HANDLER => return Err(IrqError::NotRegistered(irq)),
+        handler => unsafe { mem::transmute::<usize, IrqHandler>(handler) },
+    };
+    set_irq_masked(irq, true);
+    Ok(handler)
+}
+
+/// IRQ `irq`に登録されているハンドラ
+pub fn irq_handler(irq: u8) -> Option<IrqHandler> {
+    match HANDLERS.get(usize::from(irq))?.load(Ordering::Acquire) {
+        NO_HANDLER => None,
+        // 登録時にIrqHandlerから変換した値なので，元に戻せる
+        handler => Some(unsafe { mem::transmute::<usize, IrqHandler>(handler) }),
+    }
+}
+
+/// IRQ `irq`をマスクする，またはマスクを外す
+///
+/// APICを使っていればI/O APICの，そうでなければ8259 PICの設定を変える
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    if apic::local_apic().is_some() {
+        ioapic::set_irq_masked(irq, masked);
+        return;
+    }
+    interrupts::without_interrupts(|| unsafe {
+        let mut pics = PICS.lock();
+        let [master, slave] = pics.read_masks();
+        let mut masks = u16::from(master) | u16::from(slave) << 8;
+        if masked {
+            masks |= 1 << irq;
+        } else {
+            masks &= !(1 << irq);
+        }
+        pics.write_masks(masks as u8, (masks >> 8) as u8);
+    });
+}
+
+/// 割り込みの処理が終わったことを，使っている割り込みコントローラに伝える
+fn end_of_interrupt(irq: u8) {
+    match apic::local_apic() {
+        Some(local_apic) => local_apic.end_of_interrupt(),
+        None => unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq) },
+    }
+}
+
+/// 8259 PICのコマンドポート(マスタ・スレーブ)
+const PIC_COMMAND_PORTS: [u16; 2] = [0x20, 0xa0];
+/// OCW3: 次にコマンドポートを読んだときにISR(処理中の割り込み)を返させる
+const PIC_READ_ISR: u8 = 0x0b;
+/// スレーブのPICがつながっているマスタのIRQ
+const PIC_CASCADE_IRQ: u8 = 2;
+
+/// 8259 PICのISRを読み，IRQ `irq`が本当に処理中かどうかを返す
+///
+/// PICは割り込み要求が途中で消えると，IRQ 7(スレーブなら15)としてスプリアス割り込みを送る
+/// そのときはISRのビットが立っていない
+fn pic_in_service(irq: u8) -> bool {
+    let mut command: Port<u8> = Port::new(PIC_COMMAND_PORTS[usize::from(irq / 8)]);
+    unsafe {
+        command.write(PIC_READ_ISR);
+        command.read() & (1 << (irq % 8)) != 0
+    }
+}
+
+/// 全てのIRQのスタブから呼ばれ，登録されたハンドラを呼んでからEOIを送る
+fn dispatch(irq: u8) {
+    // 8259 PICのスプリアス割り込みはハンドラを呼ばず，処理中でない割り込みのEOIも送らない
+    if apic::local_apic().is_none() && (irq == 7 || irq == 15) && !pic_in_service(irq) {
+        // スレーブのスプリアス割り込みでも，マスタはカスケードのIRQとして受け付けている
+        if irq == 15 {
+            end_of_interrupt(PIC_CASCADE_IRQ);
+        }
+        return;
+    }
+    if let Some(handler) = irq_handler(irq) {
+        handler(irq);
+    }
+    end_of_interrupt(irq);
+}
+
+/// IRQ番号ごとに，`dispatch`を呼ぶだけの割り込みハンドラを作る
+macro_rules! irq_stubs {
+    ($($name:ident => $irq:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        const IRQ_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); ISA_IRQS] = [$($name),*];
+    };
+}
+
+irq_stubs! {
+    irq_0 => 0, irq_1 => 1, irq_2 => 2, irq_3 => 3,
+    irq_4 => 4, irq_5 => 5, irq_6 => 6, irq_7 => 7,
+    irq_8 => 8, irq_9 => 9, irq_10 => 10, irq_11 => 11,
+    irq_12 => 12, irq_13 => 13, irq_14 => 14, irq_15 => 15,
+}
+
+/// ベクタ`PIC_1_OFFSET + n`にIRQ `n`のスタブを設定する
+pub(super) fn set_stub_handlers(idt: &mut InterruptDescriptorTable) {
+    for (irq, stub) in IRQ_STUBS.iter().enumerate() {
+        idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(*stub);
+    }
+}
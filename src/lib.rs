@@ -11,12 +11,17 @@ extern crate alloc; // heap allocation/deallocation crate
 
 use core::panic::PanicInfo;
 
+pub mod acpi;
 pub mod allocator;
+pub mod backtrace;
+pub mod fmt_buffer;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
 pub mod serial;
 pub mod task;
+pub mod time;
+pub mod usermode;
 pub mod vga_buffer;
 
 /// allocation失敗時に呼び出されるハンドラ
@@ -30,9 +35,12 @@ pub fn init() {
     gdt::init();
     // Interrupt Descriptor Table（割り込み記述子表）を読み込む
     interrupts::init_idt();
-    // PICの初期化
-    // PICの設定を間違って設定すると未定義動作になるのでunsafe
-    unsafe { interrupts::PICS.lock().initialize() };
+    // 例外を起こしてもよいコピー関数の命令を例外表に登録する
+    memory::checked_copy::init().expect("failed to register checked copy fixup");
+    // PICの初期化と，タイマ・キーボードのハンドラの登録
+    interrupts::init_irqs();
+    // タイマ割り込みの周期を設定する
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -72,6 +80,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error:{}\n", info);
+    serial_println!("{}", backtrace::Backtrace::capture());
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
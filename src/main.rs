@@ -44,9 +44,15 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+    memory::print_memory_map(&boot_info.memory_map);
+    if let Err(err) = blog_os::interrupts::init_apic() {
+        println!("APIC is not available, using 8259 PIC: {:?}", err);
+    }
 
     #[cfg(test)]
     test_main();
@@ -73,6 +79,7 @@ async fn example_task() {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    println!("{}", blog_os::backtrace::Backtrace::capture());
     blog_os::hlt_loop();
 }
 
@@ -1,50 +1,188 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
-use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PhysFrame, Size4KiB};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::UnmapError;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PhysFrame, Size4KiB,
+};
 use x86_64::PhysAddr;
 use x86_64::{
     structures::paging::{OffsetPageTable, PageTable},
     VirtAddr,
 };
 
+pub mod address_space;
+pub mod buddy;
+pub mod checked_copy;
+pub mod cow;
+pub mod inspect;
+pub mod mmio;
+pub mod protection;
+pub mod region;
+pub mod report;
+pub mod vmalloc;
+
+pub use address_space::AddressSpace;
+pub use buddy::BuddyFrameAllocator;
+pub use checked_copy::{copy_from_checked, copy_to_checked, read_checked, CopyFault};
+pub use inspect::{dump_page_tables, for_each_mapped_range, MappedRange};
+pub use mmio::{map_mmio, MmioRegion};
+pub use protection::find_writable_executable;
+pub use report::{frame_usage, print_memory_map, FrameUsage};
+pub use vmalloc::{vfree, vmalloc};
+
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        // 解放されたフレームがあれば，それを先に再利用する
+        if let Some(frame) = self.free_list {
+            self.free_list = unsafe { self.read_next_free(frame) };
+            return Some(frame);
+        }
+
+        // まだ一度も割り当てていないフレームを，usableな領域の先頭から順に切り出す
+        // 領域をまたぐときだけ次の領域を探すので，ならしO(1)で済む
+        while let Some(region) = self.memory_map.get(self.region) {
+            if region.region_type == MemoryRegionType::Usable && self.next < region.range.end_addr()
+            {
+                let frame = PhysFrame::containing_address(PhysAddr::new(self.next));
+                self.next += 4096;
+                return Some(frame);
+            }
+            self.region += 1;
+            if let Some(next_region) = self.memory_map.get(self.region) {
+                self.next = next_region.range.start_addr();
+            }
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        // 解放されたフレーム自体に次の空きフレームのアドレスを書き込んでリストの先頭につなぐ
+        self.write_next_free(frame, self.free_list);
+        self.free_list = Some(frame);
     }
 }
 
 /// bootloaderのメモリマップから，使用可能な
 /// Frameを返すFrameAllocator
+///
+/// 解放されたフレームは，フレーム自体を連結リストのノードにして管理する
+/// 割り当て・解放ともにメモリマップを辿り直さないのでO(1)で済む
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
-    next: usize,
+    physical_memory_offset: VirtAddr,
+    /// 次に切り出すメモリ領域のインデックス
+    region: usize,
+    /// `region`の中でまだ一度も割り当てていない最初のアドレス
+    next: u64,
+    /// 解放されたフレームの連結リストの先頭
+    free_list: Option<PhysFrame>,
 }
 
+/// 空きフレームのリストの終端を表す値
+/// (フレームのアドレスは4KiBアラインされているので，実際のフレームと区別できる)
+const FREE_LIST_END: u64 = u64::MAX;
+
 impl BootInfoFrameAllocator {
     /// 渡されたメモリマップからFrameAllocatorを作る
     ///
     /// この関数はunsafe:呼び出し元は渡された
     /// メモリマップが有効であることを保証しなければならない
     /// 特に，USABLEなフレームは実際に未使用でなければならない
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+    /// また，全物理メモリが`physical_memory_offset`(だけずらしたうえ)で
+    /// マップされていなければならない
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let next = memory_map.first().map_or(0, |r| r.range.start_addr());
         BootInfoFrameAllocator {
             memory_map,
-            next: 0,
+            physical_memory_offset,
+            region: 0,
+            next,
+            free_list: None,
+        }
+    }
+
+    /// usableな物理メモリを全て含むのに必要なフレーム番号の数
+    ///
+    /// フレームごとの表(バディアロケータのオーダー表など)の大きさに使う
+    fn frame_count(&self) -> u64 {
+        self.memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| r.range.end_addr() / 4096)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// まだ一度も割り当てていないフレームから，物理的に連続した`count`個のフレームを割り当てる
+    ///
+    /// 今の領域に収まらなければ，収まる次のusableな領域まで読み飛ばす
+    /// 読み飛ばした領域の残りのフレームは，失われないよう解放されたフレームのリストにつなぐ
+    fn allocate_contiguous(&mut self, count: u64) -> Option<PhysFrame> {
+        let size = count * 4096;
+        while let Some(region) = self.memory_map.get(self.region) {
+            if region.region_type == MemoryRegionType::Usable {
+                if self.next + size <= region.range.end_addr() {
+                    let frame = PhysFrame::containing_address(PhysAddr::new(self.next));
+                    self.next += size;
+                    return Some(frame);
+                }
+                // 収まらなかった残りは`size`より小さいので，1フレームずつつないでも高々`count`個で済む
+                while self.next < region.range.end_addr() {
+                    let frame = PhysFrame::containing_address(PhysAddr::new(self.next));
+                    unsafe { self.deallocate_frame(frame) };
+                    self.next += 4096;
+                }
+            }
+            self.region += 1;
+            if let Some(next_region) = self.memory_map.get(self.region) {
+                self.next = next_region.range.start_addr();
+            }
+        }
+        None
+    }
+
+    /// 解放されたフレームと，まだ一度も割り当てていないフレームの範囲`[start, end)`を
+    /// 順に`f`へ渡す
+    ///
+    /// 別のフレームアロケータに残りの物理メモリを引き継ぐために使う
+    fn release_unused(self, mut f: impl FnMut(PhysAddr, PhysAddr)) {
+        let mut free_list = self.free_list;
+        while let Some(frame) = free_list {
+            free_list = unsafe { self.read_next_free(frame) };
+            f(frame.start_address(), frame.start_address() + 4096u64);
+        }
+
+        for (i, region) in self.memory_map.iter().enumerate().skip(self.region) {
+            // 今の領域は途中まで割り当て済みなので`next`から，それ以降は領域全体を渡す
+            let start = if i == self.region {
+                self.next
+            } else {
+                region.range.start_addr()
+            };
+            if region.region_type == MemoryRegionType::Usable && start < region.range.end_addr() {
+                f(PhysAddr::new(start), PhysAddr::new(region.range.end_addr()));
+            }
+        }
+    }
+
+    /// 空きフレーム`frame`に書き込まれた，次の空きフレームを読む
+    unsafe fn read_next_free(&self, frame: PhysFrame) -> Option<PhysFrame> {
+        let virt = self.physical_memory_offset + frame.start_address().as_u64();
+        match virt.as_ptr::<u64>().read() {
+            FREE_LIST_END => None,
+            addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
         }
     }
-    /// メモリマップによって指定されたusableなフレームのイテレータを返す
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // メモリマップからusableな領域を得る
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // それぞれの領域のアドレス範囲にmapで変換する
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        // フレームの開始アドレスのイテレータへと変換
-        // 4KiBごと
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // 開始アドレスからPhysFrame型を作る
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+
+    /// 空きフレーム`frame`に次の空きフレームを書き込む
+    unsafe fn write_next_free(&mut self, frame: PhysFrame, next: Option<PhysFrame>) {
+        let virt = self.physical_memory_offset + frame.start_address().as_u64();
+        let next = next.map_or(FREE_LIST_END, |f| f.start_address().as_u64());
+        virt.as_mut_ptr::<u64>().write(next);
     }
 }
 
@@ -133,7 +271,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
     let mut frame = level_4_page_table;
 
     // 複数層のページテーブルをたどる
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         // フレームをページテーブルの参照に変換する
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
@@ -144,7 +282,25 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported!"),
+            Err(FrameError::HugeFrame) => {
+                // HUGE_PAGEフラグはレベルによって意味が変わる
+                // P3なら1GiBページ，P2なら2MiBページを指し，そこで変換が終わる
+                // P1ではPATビットなので通常の4KiBフレームとして扱う
+                let huge_page_size: u64 = match level {
+                    1 => 1 << 30,
+                    2 => 1 << 21,
+                    3 => {
+                        frame = PhysFrame::containing_address(entry.addr());
+                        continue;
+                    }
+                    _ => return None,
+                };
+                // huge pageのエントリではビット12がPATビットになるので，アドレスから除く
+                let page_start = entry.addr().as_u64() & !(huge_page_size - 1);
+                return Some(PhysAddr::new(
+                    page_start + (addr.as_u64() & (huge_page_size - 1)),
+                ));
+            }
         };
     }
 
@@ -162,6 +318,93 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
 /// につながるため、この関数は一度しか呼び出してはならない。
 /// 'static はカーネル実行中はずっと生存する
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    protection::enable_no_execute();
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
+
+/// `init`で渡された物理メモリのオフセット(0は未初期化を表す)
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// 全物理メモリがマップされている仮想アドレスのオフセットを返す
+///
+/// `init`の前は`None`を返す
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset)),
+    }
+}
+
+/// カーネル全体で共有するページテーブルとフレームアロケータ
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BuddyFrameAllocator,
+}
+
+impl KernelMemory {
+    /// `page`のマッピングを外し，マップされていたフレームをフレームアロケータに返す
+    ///
+    /// この関数はunsafeである：呼び出し元は，そのページやフレームへの
+    /// 参照がもう残っていないことを保証しなければならない
+    pub unsafe fn unmap_page(&mut self, page: Page) -> Result<(), UnmapError> {
+        let (frame, flush) = self.mapper.unmap(page)?;
+        flush.flush();
+        self.frame_allocator.deallocate_frame(frame);
+        Ok(())
+    }
+}
+
+static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+/// ページテーブルとフレームアロケータを登録して，
+/// ヒープアロケータや割り込みハンドラなどカーネルのどこからでも使えるようにする
+///
+/// 起動時のフレームアロケータがまだ割り当てていないフレームは
+/// `BuddyFrameAllocator`に引き継ぎ，以降はそちらから割り当てる
+pub fn init_kernel_memory(
+    mapper: OffsetPageTable<'static>,
+    mut boot_frame_allocator: BootInfoFrameAllocator,
+) {
+    use x86_64::instructions::interrupts;
+
+    // BootInfoFrameAllocatorを作ったとき(init)に呼び出し元が条件を保証している
+    // 書き込み時コピーの参照カウントの表は，ページフォルトハンドラから使うのでヒープには置かない
+    unsafe { cow::init(&mut boot_frame_allocator) };
+    let frame_allocator = unsafe { BuddyFrameAllocator::init(boot_frame_allocator) };
+    interrupts::without_interrupts(|| {
+        // ブートローダーが作ったマッピングも含め，書き込み可能かつ実行可能なページをなくす
+        unsafe { protection::enforce_w_xor_x() };
+        *KERNEL_MEMORY.lock() = Some(KernelMemory {
+            mapper,
+            frame_allocator,
+        });
+    });
+    // 例外用やユーザモードからの割り込み用のスタックからはみ出したときにページフォルトになるようにする
+    crate::gdt::protect_kernel_stacks();
+}
+
+/// 登録されたページテーブルとフレームアロケータを使って`f`を実行する
+///
+/// 登録前，または(割り込まれたコードなどが)すでに使用中の場合は`None`を返す
+/// シングルコアでは使用中のロックを待つと二度と解放されないので，待たずに諦める
+/// `f`の中でヒープ割り当てをしてはならない(ヒープの拡張がこのロックを必要とするため)
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut kernel_memory = KERNEL_MEMORY.try_lock()?;
+        kernel_memory.as_mut().map(f)
+    })
+}
+
+/// 処理できるページフォルトなら処理して`true`を返す
+///
+/// `false`を返したフォルトは致命的なものとして扱う
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // カーネルのエントリを取り込めば済むなら，遅延割り当てなどより先に処理する
+    address_space::handle_missing_kernel_entry(addr, error_code)
+        || region::handle_lazy_fault(addr, error_code)
+        || cow::handle_cow_fault(addr, error_code)
+}
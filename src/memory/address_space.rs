@@ -0,0 +1,259 @@
+use super::{protection, with_kernel_memory, KernelMemory};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// ユーザ空間として使う仮想アドレスの範囲
+///
+/// レベル4テーブルのインデックス64..128に相当する
+/// カーネルはこの範囲を使わないので，アドレス空間ごとに別のマッピングを持てる
+/// それ以外のエントリは全てのアドレス空間でカーネルのものを共有する
+pub const USER_SPACE_START: u64 = 0x_2000_0000_0000;
+pub const USER_SPACE_END: u64 = 0x_4000_0000_0000;
+
+/// ユーザ空間に対応するレベル4テーブルのインデックス
+const USER_P4_INDEXES: core::ops::Range<usize> =
+    (USER_SPACE_START >> 39) as usize..(USER_SPACE_END >> 39) as usize;
+
+/// 独自のレベル4テーブルを持つアドレス空間
+///
+/// ユーザ空間の範囲は空間ごとに独立し，それ以外はカーネルのマッピングを共有する
+/// ユーザページのフレームと，ユーザ空間のために作ったページテーブルのフレームは
+/// アドレス空間が破棄されるときに全て解放される
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// カーネルのマッピングを共有する，ユーザ空間が空のアドレス空間を作る
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        with_kernel_memory(|kernel_memory| {
+            let level_4_frame = kernel_memory
+                .frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let address_space = AddressSpace { level_4_frame };
+            let table = address_space.level_4_table(kernel_memory.mapper.phys_offset());
+            table.zero();
+            address_space.sync_kernel_entries(kernel_memory);
+            Ok(address_space)
+        })
+        .unwrap_or(Err(MapToError::FrameAllocationFailed))
+    }
+
+    /// このアドレス空間のレベル4テーブルのフレーム
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// 新しいフレームを割り当ててゼロで埋め，ユーザページ`page`にマップする
+    ///
+    /// `flags`にはPRESENTとUSER_ACCESSIBLEが自動で追加される
+    /// 書き込み可能なページには`NO_EXECUTE`も立てる
+    pub fn map_user_page(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        assert!(is_user_page(page), "{:?} is not in user space", page);
+        let flags =
+            protection::w_xor_x(flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE);
+        // 途中のテーブルは同じテーブル内の他のページのために書き込みも許可しておく
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        with_kernel_memory(|kernel_memory| {
+            let frame = kernel_memory
+                .frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let offset = kernel_memory.mapper.phys_offset();
+            unsafe {
+                let frame_ptr: *mut u8 = (offset + frame.start_address().as_u64()).as_mut_ptr();
+                frame_ptr.write_bytes(0, 4096);
+            }
+            let mut mapper = self.mapper(kernel_memory);
+            let result = unsafe {
+                mapper.map_to_with_table_flags(
+                    page,
+                    frame,
+                    flags,
+                    table_flags,
+                    &mut kernel_memory.frame_allocator,
+                )
+            };
+            match result {
+                // 有効でないアドレス空間のTLBは切り替え時に破棄されるが，
+                // 有効な場合に備えてフラッシュしておく
+                Ok(flush) => {
+                    flush.flush();
+                    Ok(frame)
+                }
+                Err(err) => {
+                    unsafe { kernel_memory.frame_allocator.deallocate_frame(frame) };
+                    Err(err)
+                }
+            }
+        })
+        .unwrap_or(Err(MapToError::FrameAllocationFailed))
+    }
+
+    /// ユーザページ`page`のマッピングを外し，フレームを解放する
+    pub fn unmap_user_page(&mut self, page: Page) -> Result<(), UnmapError> {
+        assert!(is_user_page(page), "{:?} is not in user space", page);
+        with_kernel_memory(|kernel_memory| {
+            let mut mapper = self.mapper(kernel_memory);
+            let (frame, flush) = mapper.unmap(page)?;
+            flush.flush();
+            unsafe { kernel_memory.frame_allocator.deallocate_frame(frame) };
+            Ok(())
+        })
+        .unwrap_or(Err(UnmapError::PageNotMapped))
+    }
+
+    /// CR3を書き換えて，このアドレス空間に切り替える
+    ///
+    /// 切り替える前に，作成後にカーネルが追加したマッピングを取り込む
+    ///
+    /// この関数はunsafeである：呼び出し元は，切り替え前のアドレス空間の
+    /// ユーザページへの参照が残っていないことを保証しなければならない
+    pub unsafe fn activate(&self) {
+        with_kernel_memory(|kernel_memory| self.sync_kernel_entries(kernel_memory))
+            .expect("kernel memory is not available");
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    /// レベル4テーブルのユーザ空間以外のエントリを，カーネルのテーブルからコピーする
+    fn sync_kernel_entries(&self, kernel_memory: &mut KernelMemory) {
+        let table = self.level_4_table(kernel_memory.mapper.phys_offset());
+        let kernel_table = kernel_memory.mapper.level_4_table();
+        for (index, entry) in table.iter_mut().enumerate() {
+            if !USER_P4_INDEXES.contains(&index) {
+                *entry = kernel_table[index].clone();
+            }
+        }
+    }
+
+    /// 物理メモリのマッピング越しに，このアドレス空間のレベル4テーブルを返す
+    fn level_4_table(&self, physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+        let virt = physical_memory_offset + self.level_4_frame.start_address().as_u64();
+        unsafe { &mut *virt.as_mut_ptr() }
+    }
+
+    fn mapper(&self, kernel_memory: &KernelMemory) -> OffsetPageTable<'static> {
+        let offset = kernel_memory.mapper.phys_offset();
+        unsafe { OffsetPageTable::new(self.level_4_table(offset), offset) }
+    }
+}
+
+impl Drop for AddressSpace {
+    /// ユーザページのフレームと，ユーザ空間のページテーブルのフレームを全て解放する
+    fn drop(&mut self) {
+        let (active, _) = Cr3::read();
+        assert_ne!(
+            active, self.level_4_frame,
+            "cannot destroy the active address space"
+        );
+
+        with_kernel_memory(|kernel_memory| {
+            let offset = kernel_memory.mapper.phys_offset();
+            let table = self.level_4_table(offset);
+            for index in USER_P4_INDEXES {
+                if let Ok(frame) = table[index].frame() {
+                    unsafe { free_table(frame, 3, offset, &mut kernel_memory.frame_allocator) };
+                }
+            }
+            unsafe {
+                kernel_memory
+                    .frame_allocator
+                    .deallocate_frame(self.level_4_frame)
+            };
+        })
+        .expect("kernel memory is not available");
+    }
+}
+
+/// CR3を書き換えて，カーネルのアドレス空間に戻る
+///
+/// この関数はunsafeである：呼び出し元は，切り替え前のアドレス空間の
+/// ユーザページへの参照が残っていないことを保証しなければならない
+pub unsafe fn activate_kernel() {
+    let level_4_frame =
+        with_kernel_memory(kernel_level_4_frame).expect("kernel memory is not available");
+    let (_, flags) = Cr3::read();
+    Cr3::write(level_4_frame, flags);
+}
+
+/// カーネルのレベル4テーブルのフレーム
+fn kernel_level_4_frame(kernel_memory: &mut KernelMemory) -> PhysFrame {
+    let offset = kernel_memory.mapper.phys_offset();
+    let table_addr = VirtAddr::from_ptr(kernel_memory.mapper.level_4_table());
+    PhysFrame::containing_address(PhysAddr::new(table_addr - offset))
+}
+
+/// 有効なアドレス空間のレベル4テーブルにないカーネルのエントリを，カーネルのテーブルから取り込む
+///
+/// ユーザのアドレス空間が有効な間にカーネルが新しいレベル4のエントリを作ると，
+/// そのエントリはカーネルのテーブルにしかないので，触れたときのページフォルトで取り込む
+/// 取り込んだら`true`を返す
+pub(super) fn handle_missing_kernel_entry(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let index = usize::from(addr.p4_index());
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        || USER_P4_INDEXES.contains(&index)
+    {
+        return false;
+    }
+    with_kernel_memory(|kernel_memory| {
+        let (active, _) = Cr3::read();
+        if active == kernel_level_4_frame(kernel_memory) {
+            return false;
+        }
+        let offset = kernel_memory.mapper.phys_offset();
+        let table: &mut PageTable =
+            unsafe { &mut *(offset + active.start_address().as_u64()).as_mut_ptr() };
+        let kernel_entry = &kernel_memory.mapper.level_4_table()[index];
+        if table[index].flags().contains(PageTableFlags::PRESENT)
+            || !kernel_entry.flags().contains(PageTableFlags::PRESENT)
+        {
+            return false;
+        }
+        // 存在しないエントリはTLBに残らないので，フラッシュは要らない
+        table[index] = kernel_entry.clone();
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// `level`のページテーブルとそこからたどれるフレームを再帰的に解放する
+///
+/// レベル1のテーブルが指すフレーム(ユーザページ)も解放する
+unsafe fn free_table(
+    table_frame: PhysFrame,
+    level: u8,
+    offset: VirtAddr,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let table: &PageTable = &*(offset + table_frame.start_address().as_u64()).as_ptr();
+    for entry in table.iter() {
+        // ユーザ空間にはhuge pageを作らないので，HugeFrameのエントリは存在しない
+        if let Ok(frame) = entry.frame() {
+            if level == 1 {
+                frame_allocator.deallocate_frame(frame);
+            } else {
+                free_table(frame, level - 1, offset, frame_allocator);
+            }
+        }
+    }
+    frame_allocator.deallocate_frame(table_frame);
+}
+
+/// `page`がユーザ空間にあるかどうか
+pub fn is_user_page(page: Page) -> bool {
+    (USER_SPACE_START..USER_SPACE_END).contains(&page.start_address().as_u64())
+}
@@ -0,0 +1,219 @@
+use super::{BootInfoFrameAllocator, FREE_LIST_END};
+use bootloader::bootinfo::MemoryRegionType;
+use core::slice;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// 扱う最大のオーダー
+///
+/// オーダー`n`のブロックは物理的に連続した2^n個のフレームからなり，
+/// 2^n * 4KiBにアラインされている(オーダー10で4MiB)
+pub const MAX_ORDER: usize = 10;
+
+/// 2MiBのフレーム1つに相当するオーダー
+const HUGE_FRAME_ORDER: usize = 9;
+
+/// そのフレームがどの空きブロックの先頭でもないことを表す
+const NOT_FREE: u8 = u8::MAX;
+
+/// 空きブロックの先頭に格納する，双方向連結リストのノード
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+/// バディシステムによる物理フレームアロケータ
+///
+/// 2^n個の連続したフレームからなるブロックを単位に割り当てと解放を行う
+/// 大きなブロックを半分ずつに分けて要求されたサイズにし，
+/// 解放されたブロックは相方(バディ)も空いていれば結合して大きなブロックに戻す
+pub struct BuddyFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    /// オーダーごとの空きブロックのリストの先頭(フレーム番号)
+    free_lists: [u64; MAX_ORDER + 1],
+    /// フレーム番号ごとの，そのフレームから始まる空きブロックのオーダー
+    /// 解放時にバディが空いているかどうかを調べるのに使う
+    block_orders: &'static mut [u8],
+    total_frames: usize,
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// 起動時のフレームアロケータがまだ割り当てていない全てのフレームを引き継いで
+    /// BuddyFrameAllocatorを作る
+    ///
+    /// この関数はunsafeである：呼び出し元は`boot_allocator`が
+    /// `BootInfoFrameAllocator::init`の条件を満たしていることを保証しなければならない
+    pub unsafe fn init(mut boot_allocator: BootInfoFrameAllocator) -> Self {
+        let total_frames = boot_allocator
+            .memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| (r.range.end_addr() - r.range.start_addr()) / 4096)
+            .sum::<u64>() as usize;
+        let frame_count = boot_allocator.frame_count();
+
+        // フレーム番号ごとのオーダー表は，連続したフレームに置いて
+        // 物理メモリのマッピング越しにアクセスする
+        let table_frames = (frame_count + 4095) / 4096;
+        let table_start = boot_allocator
+            .allocate_contiguous(table_frames)
+            .expect("no memory for buddy allocator metadata");
+        let physical_memory_offset = boot_allocator.physical_memory_offset;
+        let table_ptr: *mut u8 =
+            (physical_memory_offset + table_start.start_address().as_u64()).as_mut_ptr();
+        let block_orders = slice::from_raw_parts_mut(table_ptr, frame_count as usize);
+        block_orders.fill(NOT_FREE);
+
+        let mut allocator = BuddyFrameAllocator {
+            physical_memory_offset,
+            free_lists: [FREE_LIST_END; MAX_ORDER + 1],
+            block_orders,
+            total_frames,
+            free_frames: 0,
+        };
+        boot_allocator.release_unused(|start, end| allocator.add_range(start, end));
+        allocator
+    }
+
+    /// 2^`order`個の連続したフレームからなるブロックを割り当て，先頭のフレームを返す
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        // 要求以上で最小の空きブロックを探す
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != FREE_LIST_END)?;
+        let frame_number = self.pop(current);
+        // 大きすぎるブロックは半分に分け，後ろ半分を一つ下のオーダーのリストに戻す
+        while current > order {
+            current -= 1;
+            self.push(current, frame_number + (1 << current));
+        }
+        self.free_frames -= 1 << order;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            frame_number * 4096,
+        )))
+    }
+
+    /// `allocate(order)`で割り当てたブロックを解放する
+    ///
+    /// この関数はunsafeである：呼び出し元は`frame`から始まるブロックが
+    /// 同じ`order`で割り当てられたもので，もう使われていないことを保証しなければならない
+    pub unsafe fn deallocate(&mut self, frame: PhysFrame, order: usize) {
+        let mut frame_number = frame.start_address().as_u64() / 4096;
+        let mut current = order;
+        // バディも同じオーダーの空きブロックなら結合して一つ上のオーダーにする
+        while current < MAX_ORDER {
+            let buddy = frame_number ^ (1 << current);
+            if self.block_orders.get(buddy as usize) != Some(&(current as u8)) {
+                break;
+            }
+            self.remove(current, buddy);
+            frame_number = frame_number.min(buddy);
+            current += 1;
+        }
+        self.push(current, frame_number);
+        self.free_frames += 1 << order;
+    }
+
+    /// 管理している(usableな)フレームの総数
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// 空いているフレームの数
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// `frame`から始まる空きブロックのオーダー．空きブロックの先頭でなければ`None`
+    pub fn free_block_order(&self, frame: PhysFrame) -> Option<usize> {
+        let frame_number = frame.start_address().as_u64() / 4096;
+        match *self.block_orders.get(frame_number as usize)? {
+            NOT_FREE => None,
+            order => Some(usize::from(order)),
+        }
+    }
+
+    /// 物理アドレスの範囲`[start, end)`を，アラインされた最大のブロックに分けて空きにする
+    fn add_range(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut frame_number = start.align_up(4096u64).as_u64() / 4096;
+        let end_frame_number = end.as_u64() / 4096;
+        while frame_number < end_frame_number {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&o| {
+                    frame_number % (1 << o) == 0 && frame_number + (1 << o) <= end_frame_number
+                })
+                .unwrap();
+            let frame = PhysFrame::containing_address(PhysAddr::new(frame_number * 4096));
+            unsafe { self.deallocate(frame, order) };
+            frame_number += 1 << order;
+        }
+    }
+
+    /// フレーム番号`frame_number`のブロックに置かれたリストのノードを返す
+    fn block(&self, frame_number: u64) -> *mut FreeBlock {
+        (self.physical_memory_offset + frame_number * 4096).as_mut_ptr()
+    }
+
+    /// オーダー`order`のリストの先頭にブロックを追加する
+    fn push(&mut self, order: usize, frame_number: u64) {
+        let head = self.free_lists[order];
+        unsafe {
+            self.block(frame_number).write(FreeBlock {
+                next: head,
+                prev: FREE_LIST_END,
+            });
+            if head != FREE_LIST_END {
+                (*self.block(head)).prev = frame_number;
+            }
+        }
+        self.free_lists[order] = frame_number;
+        self.block_orders[frame_number as usize] = order as u8;
+    }
+
+    /// オーダー`order`のリストの先頭のブロックを取り出す
+    fn pop(&mut self, order: usize) -> u64 {
+        let frame_number = self.free_lists[order];
+        self.remove(order, frame_number);
+        frame_number
+    }
+
+    /// オーダー`order`のリストからブロックを取り除く
+    fn remove(&mut self, order: usize, frame_number: u64) {
+        let FreeBlock { next, prev } = unsafe { self.block(frame_number).read() };
+        if prev == FREE_LIST_END {
+            self.free_lists[order] = next;
+        } else {
+            unsafe { (*self.block(prev)).next = next };
+        }
+        if next != FREE_LIST_END {
+            unsafe { (*self.block(next)).prev = prev };
+        }
+        self.block_orders[frame_number as usize] = NOT_FREE;
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.deallocate(frame, 0)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate(HUGE_FRAME_ORDER)?;
+        Some(PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let frame = PhysFrame::containing_address(frame.start_address());
+        self.deallocate(frame, HUGE_FRAME_ORDER)
+    }
+}
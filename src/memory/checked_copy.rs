@@ -0,0 +1,102 @@
+use crate::interrupts::fixup::{self, FixupError};
+use core::arch::global_asm;
+use core::fmt;
+use core::mem::{size_of, MaybeUninit};
+use x86_64::VirtAddr;
+
+// `len`バイトを`rep movsb`でコピーし，コピーできなかったバイト数を返す
+//
+// `rep movsb`で例外が起きると例外表によって`checked_copy_fixup`に飛ぶ
+// その時点でRCXに残っているバイト数を返り値にする
+global_asm!(
+    ".pushsection .text.checked_copy, \"ax\"",
+    ".global checked_copy_bytes",
+    ".global checked_copy_start",
+    ".global checked_copy_end",
+    ".global checked_copy_fixup",
+    "checked_copy_bytes:",
+    "    mov rcx, rdx",
+    "checked_copy_start:",
+    "    rep movsb",
+    "checked_copy_end:",
+    "    xor eax, eax",
+    "    ret",
+    "checked_copy_fixup:",
+    "    mov rax, rcx",
+    "    ret",
+    ".popsection",
+);
+
+extern "C" {
+    fn checked_copy_bytes(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn checked_copy_start();
+    fn checked_copy_end();
+    fn checked_copy_fixup();
+}
+
+/// コピーの途中でマップされていないアドレスなどに触れた
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyFault {
+    /// 例外が起きるまでにコピーできたバイト数
+    pub copied: usize,
+}
+
+impl fmt::Display for CopyFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "memory fault after copying {} bytes", self.copied)
+    }
+}
+
+/// コピー関数の命令を例外表に登録する
+///
+/// `crate::init`から一度だけ呼ばれる．コピーはバックトレースなど停止の途中でも使うので，
+/// 表に空きがないといった失敗はコピーのときではなく起動時に知らせる
+pub(crate) fn init() -> Result<(), FixupError> {
+    let addr = |f: unsafe extern "C" fn()| VirtAddr::new(f as usize as u64);
+    fixup::register_fixup(
+        addr(checked_copy_start),
+        addr(checked_copy_end),
+        addr(checked_copy_fixup),
+    )
+}
+
+/// `dst`と`src`の間で`len`バイトをコピーする．どちらかで例外が起きてもエラーを返すだけで済む
+unsafe fn copy_bytes(dst: *mut u8, src: *const u8, len: usize) -> Result<(), CopyFault> {
+    match checked_copy_bytes(dst, src, len) {
+        0 => Ok(()),
+        remaining => Err(CopyFault {
+            copied: len - remaining,
+        }),
+    }
+}
+
+/// `src`から`dst.len()`バイトを読み出す
+///
+/// `src`がマップされていない，あるいは非canonicalなアドレスを指していても停止せず，
+/// `CopyFault`を返す．遅延割り当ての領域などはいつも通りpage faultで解決される
+///
+/// この関数はunsafeである：呼び出し元は`src`から読み出すことに副作用
+/// (MMIOのレジスタなど)がないことを保証しなければならない
+pub unsafe fn copy_from_checked(dst: &mut [u8], src: *const u8) -> Result<(), CopyFault> {
+    copy_bytes(dst.as_mut_ptr(), src, dst.len())
+}
+
+/// `src`の内容を`dst`に書き込む
+///
+/// `dst`がマップされていない，書き込めないといった場合は停止せず，`CopyFault`を返す
+///
+/// この関数はunsafeである：呼び出し元は`dst`が書き換えてよいメモリを指していることを
+/// 保証しなければならない
+pub unsafe fn copy_to_checked(dst: *mut u8, src: &[u8]) -> Result<(), CopyFault> {
+    copy_bytes(dst, src.as_ptr(), src.len())
+}
+
+/// `src`から`T`を1つ読み出す
+///
+/// この関数はunsafeである：`copy_from_checked`と同じ条件に加えて，
+/// 読み出したバイト列が`T`として正しい値であることを保証しなければならない
+pub unsafe fn read_checked<T: Copy>(src: *const T) -> Result<T, CopyFault> {
+    let mut value = MaybeUninit::<T>::uninit();
+    copy_bytes(value.as_mut_ptr().cast(), src.cast(), size_of::<T>())?;
+    Ok(value.assume_init())
+}
@@ -0,0 +1,214 @@
+use super::protection::w_xor_x;
+use super::{with_kernel_memory, BootInfoFrameAllocator, KernelMemory};
+use core::slice;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{MapToError, MappedFrame, TranslateResult, UnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+/// 書き込み時にコピーするページであることを表す，OSが自由に使えるビット
+///
+/// このビットが立っているページは読み取り専用でマップされ，
+/// 書き込みによる保護違反のフォルトでフレームを複製して書き込み可能にする
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// フレーム番号ごとの，そのフレームを共有しているページの数から1を引いた値
+///
+/// ページフォルトハンドラから更新するのでヒープは使わず，`init`で物理フレームの数だけの
+/// 表を確保しておく(0なら共有されていない)
+static FRAME_REFS: Mutex<Option<&'static mut [u32]>> = Mutex::new(None);
+
+/// 参照カウントの表を，起動時のフレームアロケータから連続したフレームに確保する
+///
+/// `init_kernel_memory`から，フレームをバディアロケータに引き継ぐ前に呼ばれる
+///
+/// この関数はunsafeである：呼び出し元は`boot_allocator`が
+/// `BootInfoFrameAllocator::init`の条件を満たしていることを保証しなければならない
+pub(super) unsafe fn init(boot_allocator: &mut BootInfoFrameAllocator) {
+    let frame_count = boot_allocator.frame_count();
+    let table_frames = (frame_count * 4 + 4095) / 4096;
+    let table_start = boot_allocator
+        .allocate_contiguous(table_frames)
+        .expect("no memory for copy-on-write reference counts");
+    let table_ptr: *mut u32 =
+        (boot_allocator.physical_memory_offset + table_start.start_address().as_u64()).as_mut_ptr();
+    let table = slice::from_raw_parts_mut(table_ptr, frame_count as usize);
+    table.fill(0);
+    interrupts::without_interrupts(|| *FRAME_REFS.lock() = Some(table));
+}
+
+/// 表の`frame`のエントリ．表の範囲外のフレームは共有できない
+fn shared_refs<'a>(
+    table: &'a mut Option<&'static mut [u32]>,
+    frame: PhysFrame,
+) -> Option<&'a mut u32> {
+    let index = frame.start_address().as_u64() / 4096;
+    table.as_deref_mut()?.get_mut(index as usize)
+}
+
+/// 共有マッピングの作成に失敗した理由
+#[derive(Debug)]
+pub enum CowError {
+    /// 共有元のページが4KiBページとしてマップされていない
+    SourceNotMapped,
+    /// 共有先のページのマップに失敗した
+    MapFailed(MapToError<Size4KiB>),
+    /// ページテーブルが登録されていないか，使用中
+    KernelMemoryUnavailable,
+    /// 参照カウントの表で管理していないフレーム(usableな物理メモリの外)
+    UntrackedFrame,
+}
+
+/// `frame`を参照しているページの数を返す
+pub fn ref_count(frame: PhysFrame) -> usize {
+    interrupts::without_interrupts(|| {
+        shared_refs(&mut FRAME_REFS.lock(), frame).map_or(1, |&mut extra| extra as usize + 1)
+    })
+}
+
+/// `src`にマップされているフレームを，`dst`にも書き込み時コピーとしてマップする
+///
+/// `src`が書き込み可能だった場合，両方のページを読み取り専用にして`COPY_ON_WRITE`の印をつける．
+/// どちらかに書き込むとフォルトが起き，そのページだけがフレームの複製を受け取る
+pub fn share_copy_on_write(src: Page, dst: Page) -> Result<PhysFrame, CowError> {
+    interrupts::without_interrupts(|| {
+        let frame = with_kernel_memory(|kernel_memory| mapped_frame(kernel_memory, src))
+            .ok_or(CowError::KernelMemoryUnavailable)?
+            .ok_or(CowError::SourceNotMapped)?
+            .0;
+        *shared_refs(&mut FRAME_REFS.lock(), frame).ok_or(CowError::UntrackedFrame)? += 1;
+
+        let result = with_kernel_memory(|kernel_memory| {
+            let (_, mut flags) = mapped_frame(kernel_memory, src).unwrap();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(COPY_ON_WRITE);
+                unsafe { kernel_memory.mapper.update_flags(src, flags) }
+                    .unwrap()
+                    .flush();
+            }
+            unsafe {
+                kernel_memory
+                    .mapper
+                    .map_to(dst, frame, flags, &mut kernel_memory.frame_allocator)
+            }
+            .map(|flush| flush.flush())
+        })
+        .unwrap_or(Err(MapToError::FrameAllocationFailed));
+
+        match result {
+            Ok(()) => Ok(frame),
+            Err(err) => {
+                release_ref(frame);
+                Err(CowError::MapFailed(err))
+            }
+        }
+    })
+}
+
+/// 書き込み時コピーで共有しているかもしれない`page`のマッピングを外す
+///
+/// フレームは他に参照しているページがなくなったときにだけ解放する
+///
+/// この関数はunsafeである：呼び出し元は，そのページへの参照が
+/// もう残っていないことを保証しなければならない
+pub unsafe fn unmap_shared(page: Page) -> Result<(), UnmapError> {
+    interrupts::without_interrupts(|| {
+        let (frame, flush) = with_kernel_memory(|kernel_memory| kernel_memory.mapper.unmap(page))
+            .expect("kernel memory is not available")?;
+        flush.flush();
+        if release_ref(frame) {
+            with_kernel_memory(|kernel_memory| {
+                kernel_memory.frame_allocator.deallocate_frame(frame)
+            })
+            .expect("kernel memory is not available");
+        }
+        Ok(())
+    })
+}
+
+/// 書き込み時コピーのページへの書き込みで起きた保護違反のフォルトを処理する
+///
+/// フレームを他のページと共有していれば複製してからマップし直し，
+/// 共有していなければそのまま書き込み可能にする．処理できたら`true`を返す
+pub fn handle_cow_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let write_violation =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(write_violation) {
+        return false;
+    }
+    let page = Page::containing_address(addr);
+    with_kernel_memory(|kernel_memory| {
+        let (frame, flags) = match mapped_frame(kernel_memory, page) {
+            Some(mapping) if mapping.1.contains(COPY_ON_WRITE) => mapping,
+            _ => return false,
+        };
+        let mut table = match FRAME_REFS.try_lock() {
+            Some(table) => table,
+            None => return false,
+        };
+        let new_flags = w_xor_x((flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE);
+
+        let extra = match shared_refs(&mut table, frame) {
+            Some(extra) if *extra > 0 => extra,
+            _ => {
+                // 最後の参照なので，複製せずに書き込み可能にする
+                unsafe { kernel_memory.mapper.update_flags(page, new_flags) }
+                    .unwrap()
+                    .flush();
+                return true;
+            }
+        };
+
+        let copy = match kernel_memory.frame_allocator.allocate_frame() {
+            Some(copy) => copy,
+            None => return false,
+        };
+        let offset = kernel_memory.mapper.phys_offset();
+        unsafe {
+            let src: *const u8 = (offset + frame.start_address().as_u64()).as_ptr();
+            let dst: *mut u8 = (offset + copy.start_address().as_u64()).as_mut_ptr();
+            dst.copy_from_nonoverlapping(src, Page::<Size4KiB>::SIZE as usize);
+        }
+        *extra -= 1;
+
+        let (_, flush) = kernel_memory.mapper.unmap(page).unwrap();
+        flush.ignore();
+        unsafe {
+            kernel_memory
+                .mapper
+                .map_to(page, copy, new_flags, &mut kernel_memory.frame_allocator)
+        }
+        .unwrap()
+        .flush();
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// `page`にマップされている4KiBのフレームとフラグを返す
+fn mapped_frame(kernel_memory: &KernelMemory, page: Page) -> Option<(PhysFrame, PageTableFlags)> {
+    match kernel_memory.mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => Some((frame, flags)),
+        _ => None,
+    }
+}
+
+/// `frame`の参照を1つ減らし，それが最後の参照だったら`true`を返す
+fn release_ref(frame: PhysFrame) -> bool {
+    match shared_refs(&mut FRAME_REFS.lock(), frame) {
+        Some(extra) if *extra > 0 => {
+            *extra -= 1;
+            false
+        }
+        _ => true,
+    }
+}
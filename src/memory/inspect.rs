@@ -0,0 +1,155 @@
+use super::physical_memory_offset;
+use crate::serial_println;
+use core::fmt;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// 同じページサイズ・同じフラグで，仮想アドレスも物理アドレスも連続してマップされている範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    /// 範囲のバイト数
+    pub size: u64,
+    pub phys_start: PhysAddr,
+    /// 4KiB，2MiB，1GiBのいずれか
+    pub page_size: u64,
+    /// 上位のテーブルのエントリも考慮した実際のフラグ
+    /// WRITABLEとUSER_ACCESSIBLEは全てのレベルで立っているときだけ，
+    /// NO_EXECUTEはどこか一つのレベルで立っていれば立つ
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    /// 仮想アドレス`addr`がこの範囲に含まれるかどうか
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr.as_u64() - self.start.as_u64() < self.size
+    }
+
+    /// `next`がこの範囲のすぐ後ろに続いていて，一つの範囲にまとめられるかどうか
+    fn continues_with(&self, next: &MappedRange) -> bool {
+        self.start.as_u64().wrapping_add(self.size) == next.start.as_u64()
+            && self.phys_start.as_u64() + self.size == next.phys_start.as_u64()
+            && self.page_size == next.page_size
+            && self.flags == next.flags
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        let page_size = match self.page_size {
+            0x1000 => "4KiB",
+            0x20_0000 => "2MiB",
+            _ => "1GiB",
+        };
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x} {} {}{}{}{}",
+            self.start.as_u64(),
+            self.start.as_u64().wrapping_add(self.size),
+            self.phys_start.as_u64(),
+            page_size,
+            flag(self.flags.contains(PageTableFlags::WRITABLE), 'W'),
+            flag(self.flags.contains(PageTableFlags::USER_ACCESSIBLE), 'U'),
+            flag(!self.flags.contains(PageTableFlags::NO_EXECUTE), 'X'),
+            flag(self.flags.contains(PageTableFlags::GLOBAL), 'G'),
+        )
+    }
+}
+
+/// 有効なレベル4テーブルをたどり，マップされている範囲を仮想アドレス順に`f`へ渡す
+///
+/// 連続した範囲は一つにまとめる
+/// ヒープ割り当てもロックも使わないので，パニックハンドラからも呼び出せる
+/// `memory::init`の前に呼び出した場合は何もしない
+pub fn for_each_mapped_range(mut f: impl FnMut(&MappedRange)) {
+    let physical_memory_offset = match physical_memory_offset() {
+        Some(offset) => offset,
+        None => return,
+    };
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let mut current: Option<MappedRange> = None;
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    walk_table(
+        physical_memory_offset,
+        level_4_table_frame,
+        4,
+        0,
+        inherited,
+        &mut |mapping| match &mut current {
+            Some(range) if range.continues_with(&mapping) => range.size += mapping.size,
+            _ => {
+                if let Some(range) = current.replace(mapping) {
+                    f(&range);
+                }
+            }
+        },
+    );
+    if let Some(range) = current {
+        f(&range);
+    }
+}
+
+/// マップされている全ての範囲をシリアルポートに出力する
+pub fn dump_page_tables() {
+    serial_println!("virtual range                            -> physical       page flags");
+    for_each_mapped_range(|range| {
+        serial_println!("{}", range);
+    });
+}
+
+/// `level`のページテーブルの全てのエントリを再帰的にたどり，末端のマッピングを`f`に渡す
+///
+/// `base`はこのテーブルが受け持つ仮想アドレスの先頭，
+/// `inherited`は上位のエントリから引き継ぐフラグ
+fn walk_table(
+    physical_memory_offset: VirtAddr,
+    table_frame: PhysFrame,
+    level: u8,
+    base: u64,
+    inherited: PageTableFlags,
+    f: &mut dyn FnMut(MappedRange),
+) {
+    let virt = physical_memory_offset + table_frame.start_address().as_u64();
+    let table: &PageTable = unsafe { &*virt.as_ptr() };
+    // このレベルのエントリ1つが受け持つバイト数
+    let entry_size = 1u64 << (12 + 9 * (u64::from(level) - 1));
+
+    for (index, entry) in table.iter().enumerate() {
+        let entry_flags = entry.flags();
+        if !entry_flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = base + index as u64 * entry_size;
+        let restricting = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let flags = (entry_flags - restricting)
+            | (entry_flags & inherited & restricting)
+            | (inherited & PageTableFlags::NO_EXECUTE);
+
+        // P1のエントリ，またはHUGE_PAGEの立ったP3・P2のエントリがページを指す
+        let is_page = level == 1 || (level <= 3 && entry_flags.contains(PageTableFlags::HUGE_PAGE));
+        if is_page {
+            // huge pageのエントリではビット12がPATビットになるので，アドレスから除く
+            let phys_start = PhysAddr::new(entry.addr().as_u64() & !(entry_size - 1));
+            f(MappedRange {
+                start: VirtAddr::new_truncate(start),
+                size: entry_size,
+                phys_start,
+                page_size: entry_size,
+                flags: flags - PageTableFlags::HUGE_PAGE,
+            });
+        } else {
+            let next_table = PhysFrame::containing_address(entry.addr());
+            walk_table(
+                physical_memory_offset,
+                next_table,
+                level - 1,
+                start,
+                flags,
+                f,
+            );
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use super::region::{self, Region, RegionError};
+use super::with_kernel_memory;
+use core::ops::Range;
+use core::ptr;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// `map_mmio`でデバイスのレジスタをマップする仮想アドレス範囲(レベル4テーブルのエントリ1つ分)
+pub const MMIO_AREA: Range<u64> = 0x_7700_0000_0000..0x_7780_0000_0000;
+
+/// デバイスの物理アドレス範囲をキャッシュ無効でマップしたもの
+///
+/// フレームはデバイスのものなのでフレームアロケータには返さない
+/// dropするとマッピングを外す
+#[derive(Debug)]
+pub struct MmioRegion {
+    region: Region,
+    /// 物理アドレスのページ内オフセットも含めた，`phys`に対応する仮想アドレス
+    base: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl MmioRegion {
+    /// `phys`に対応する仮想アドレス
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 先頭から`offset`バイトの位置の値を読む(volatile)
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile(self.ptr::<T>(offset)) }
+    }
+
+    /// 先頭から`offset`バイトの位置に値を書く(volatile)
+    pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile(self.ptr::<T>(offset), value) }
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + core::mem::size_of::<T>() <= self.len,
+            "mmio access out of bounds"
+        );
+        assert_eq!(
+            offset % core::mem::align_of::<T>(),
+            0,
+            "unaligned mmio access"
+        );
+        (self.base + offset as u64).as_mut_ptr()
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        with_kernel_memory(|kernel_memory| {
+            for page in self.region.pages() {
+                // デバイスのフレームなので解放しない
+                let (_frame, flush) = kernel_memory.mapper.unmap(page).unwrap();
+                flush.flush();
+            }
+        })
+        .expect("kernel memory is not available");
+        region::unregister(&self.region);
+    }
+}
+
+/// 物理アドレス`phys`から`len`バイトのデバイスのレジスタを，カーネルの仮想アドレスに
+/// `NO_CACHE | WRITE_THROUGH`でマップする
+///
+/// この関数はunsafeである：呼び出し元は，その範囲がRAMではなくデバイスのもので，
+/// 他の場所からマップされていないことを保証しなければならない
+pub unsafe fn map_mmio(phys: PhysAddr, len: usize) -> Result<MmioRegion, RegionError> {
+    let first_frame: PhysFrame = PhysFrame::containing_address(phys);
+    let last_frame: PhysFrame = PhysFrame::containing_address(phys + len.max(1) as u64 - 1u64);
+    let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+    let pages = last_frame - first_frame + 1;
+
+    let region = region::reserve_in(MMIO_AREA, "mmio", pages, None)?;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    let mapped = with_kernel_memory(|kernel_memory| {
+        for (page, frame) in region.pages().zip(frames) {
+            let result =
+                kernel_memory
+                    .mapper
+                    .map_to(page, frame, flags, &mut kernel_memory.frame_allocator);
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    for mapped_page in Page::<Size4KiB>::range(region.pages().start, page) {
+                        kernel_memory.mapper.unmap(mapped_page).unwrap().1.flush();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    })
+    .unwrap_or(Err(MapToError::FrameAllocationFailed));
+
+    match mapped {
+        Ok(()) => Ok(MmioRegion {
+            region,
+            base: region.start() + (phys - first_frame.start_address()),
+            phys,
+            len,
+        }),
+        Err(err) => {
+            region::unregister(&region);
+            Err(RegionError::MapFailed(err))
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use super::inspect::{for_each_mapped_range, MappedRange};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+/// EFERのNXEビットを立て，ページテーブルの`NO_EXECUTE`フラグを有効にする
+///
+/// NXEが立っていないと`NO_EXECUTE`は予約ビット扱いになり，立てたページに触れるとフォルトになる
+pub fn enable_no_execute() {
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+}
+
+/// カーネルのELFで扱う，実行可能なセグメントの最大数
+const MAX_TEXT_SEGMENTS: usize = 8;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+
+extern "C" {
+    /// リンカ(lld)が定義する，メモリ上のELFヘッダの位置
+    static __ehdr_start: u8;
+}
+
+/// カーネルのコードがあるセグメントの範囲
+struct KernelText {
+    segments: [(u64, u64); MAX_TEXT_SEGMENTS],
+    len: usize,
+}
+
+impl KernelText {
+    /// カーネルのELFのプログラムヘッダから，実行可能なロード可能セグメントを集める
+    ///
+    /// bootloaderはカーネルをリンクされたアドレスにそのままマップし，
+    /// 先頭のセグメントにはELFヘッダとプログラムヘッダも含まれている
+    /// ELFヘッダが見つからなければ`None`を返す
+    fn from_elf_headers() -> Option<Self> {
+        // extern staticへのaddr_of!はツールチェーンによってはunsafe
+        #[allow(unused_unsafe)]
+        let ehdr = unsafe { core::ptr::addr_of!(__ehdr_start) };
+        let read_u16 = |ptr: *const u8| unsafe { ptr.cast::<u16>().read_unaligned() };
+        let read_u32 = |ptr: *const u8| unsafe { ptr.cast::<u32>().read_unaligned() };
+        let read_u64 = |ptr: *const u8| unsafe { ptr.cast::<u64>().read_unaligned() };
+
+        if unsafe { ehdr.cast::<[u8; 4]>().read() } != *b"\x7fELF" {
+            return None;
+        }
+        let phoff = read_u64(ehdr.wrapping_add(0x20)) as usize;
+        let phentsize = usize::from(read_u16(ehdr.wrapping_add(0x36)));
+        let phnum = usize::from(read_u16(ehdr.wrapping_add(0x38)));
+
+        let mut text = KernelText {
+            segments: [(0, 0); MAX_TEXT_SEGMENTS],
+            len: 0,
+        };
+        for i in 0..phnum {
+            let phdr = ehdr.wrapping_add(phoff + i * phentsize);
+            let executable =
+                read_u32(phdr) == PT_LOAD && read_u32(phdr.wrapping_add(4)) & PF_X != 0;
+            if !executable {
+                continue;
+            }
+            let start = read_u64(phdr.wrapping_add(0x10));
+            let size = read_u64(phdr.wrapping_add(0x28));
+            assert!(text.len < MAX_TEXT_SEGMENTS, "too many executable segments");
+            text.segments[text.len] = (start, start + size);
+            text.len += 1;
+        }
+        Some(text)
+    }
+
+    /// `[start, start + size)`がコードのセグメントと重なるか
+    fn overlaps(&self, start: u64, size: u64) -> bool {
+        self.segments[..self.len]
+            .iter()
+            .any(|&(text_start, text_end)| text_start < start + size && start < text_end)
+    }
+}
+
+/// 書き込み可能かつ実行可能なページがなくなるように，有効なページテーブルのフラグを直す
+///
+/// カーネルのコード(ELFの実行可能なセグメント)は読み取り専用にし，
+/// それ以外(物理メモリのマッピングやスタックなど)には`NO_EXECUTE`を立てる
+///
+/// この関数はunsafeである：呼び出し元は`memory::init`を呼んだ後であることと，
+/// 書き込みや実行に使っているページのフラグを変えても問題ないことを保証しなければならない
+pub unsafe fn enforce_w_xor_x() {
+    let physical_memory_offset = match super::physical_memory_offset() {
+        Some(offset) => offset,
+        None => return,
+    };
+    // コードの範囲がわからないまま`NO_EXECUTE`を立てると，カーネル自身が実行できなくなる
+    let text = KernelText::from_elf_headers().expect("kernel ELF headers are not mapped");
+
+    let (level_4_table_frame, _) = Cr3::read();
+    protect_table(physical_memory_offset, level_4_table_frame, 4, 0, &text);
+    tlb::flush_all();
+}
+
+/// 書き込み可能なマッピングのフラグには`NO_EXECUTE`を立てて返す
+///
+/// 起動後に作るマッピングもW^Xを満たすよう，呼び出し元のフラグでマップする関数はこれを通す
+pub(crate) fn w_xor_x(flags: PageTableFlags) -> PageTableFlags {
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags | PageTableFlags::NO_EXECUTE
+    } else {
+        flags
+    }
+}
+
+/// 書き込み可能かつ実行可能な範囲のうち，最初のものを返す
+///
+/// 全ての範囲がW^Xを満たしていれば`None`を返す
+pub fn find_writable_executable() -> Option<MappedRange> {
+    let mut found = None;
+    for_each_mapped_range(|range| {
+        let writable = range.flags.contains(PageTableFlags::WRITABLE);
+        let executable = !range.flags.contains(PageTableFlags::NO_EXECUTE);
+        if found.is_none() && writable && executable {
+            found = Some(*range);
+        }
+    });
+    found
+}
+
+/// `level`のテーブルをたどり，書き込み可能かつ実行可能な末端のエントリのフラグを直す
+///
+/// 上位のエントリで書き込み不可または実行不可になっている部分はたどらない
+unsafe fn protect_table(
+    physical_memory_offset: VirtAddr,
+    table_frame: PhysFrame,
+    level: u8,
+    base: u64,
+    text: &KernelText,
+) {
+    let virt = physical_memory_offset + table_frame.start_address().as_u64();
+    let table: &mut PageTable = &mut *virt.as_mut_ptr();
+    let entry_size = 1u64 << (12 + 9 * (u64::from(level) - 1));
+
+    for (index, entry) in table.iter_mut().enumerate() {
+        let mut flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT)
+            || !flags.contains(PageTableFlags::WRITABLE)
+            || flags.contains(PageTableFlags::NO_EXECUTE)
+        {
+            continue;
+        }
+        let start = VirtAddr::new_truncate(base + index as u64 * entry_size);
+
+        let is_page = level == 1 || (level <= 3 && flags.contains(PageTableFlags::HUGE_PAGE));
+        if is_page {
+            if text.overlaps(start.as_u64(), entry_size) {
+                flags.remove(PageTableFlags::WRITABLE);
+            } else {
+                flags.insert(PageTableFlags::NO_EXECUTE);
+            }
+            entry.set_flags(flags);
+        } else {
+            let next_table = PhysFrame::containing_address(entry.addr());
+            protect_table(
+                physical_memory_offset,
+                next_table,
+                level - 1,
+                start.as_u64(),
+                text,
+            );
+        }
+    }
+}
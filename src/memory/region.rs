@@ -0,0 +1,440 @@
+use super::protection::w_xor_x;
+use super::with_kernel_memory;
+use core::fmt;
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// `allocate`で領域を確保するカーネルの仮想アドレス範囲
+///
+/// レベル4テーブルのエントリ1つ分(512GiB)を使う
+pub const KERNEL_REGION_AREA: Range<u64> = 0x_5500_0000_0000..0x_5580_0000_0000;
+
+/// 同時に登録できる領域の数
+///
+/// ページフォルトハンドラから参照するのでヒープは使わず，固定長の表で管理する
+const MAX_REGIONS: usize = 64;
+
+/// 前後をマップされていないガードページで挟まれた，カーネルの仮想アドレス範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    name: &'static str,
+    start: VirtAddr,
+    end: VirtAddr,
+    /// 遅延割り当ての領域なら，ページをマップするときのフラグ
+    lazy_flags: Option<PageTableFlags>,
+}
+
+impl Region {
+    /// 領域の名前(ガードページに触れたときの報告に使う)
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// 使用できる範囲の先頭
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// 使用できる範囲の末尾(この値は含まない)
+    /// スタックとして使う場合はここがスタックの先頭になる
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// 使用できる範囲のページ
+    pub fn pages(&self) -> PageRange {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end),
+        )
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// 最初にアクセスされたときにフレームを割り当てる領域か
+    pub fn is_lazy(&self) -> bool {
+        self.lazy_flags.is_some()
+    }
+
+    /// ガードページも含めて予約している範囲
+    fn reserved(&self) -> Range<u64> {
+        self.start.as_u64() - PAGE_SIZE..self.end.as_u64() + PAGE_SIZE
+    }
+}
+
+/// どちら側のガードページに触れたか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardSide {
+    /// 領域の先頭より下(スタックのオーバーフロー)
+    Below,
+    /// 領域の末尾より上(バッファのオーバーラン)
+    Above,
+}
+
+/// ガードページへのアクセス
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuardPageHit {
+    pub region: Region,
+    pub side: GuardSide,
+    pub addr: VirtAddr,
+}
+
+impl fmt::Display for GuardPageHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let region = &self.region;
+        match self.side {
+            GuardSide::Below => write!(
+                f,
+                "overflow below region `{}`: {:?} is {} bytes below its start",
+                region.name,
+                self.addr,
+                region.start - self.addr
+            )?,
+            GuardSide::Above => write!(
+                f,
+                "overrun above region `{}`: {:?} is {} bytes past its end",
+                region.name,
+                self.addr,
+                self.addr - region.end
+            )?,
+        }
+        write!(f, " ({:?}..{:?})", region.start, region.end)
+    }
+}
+
+/// 領域の確保・登録に失敗した理由
+#[derive(Debug)]
+pub enum RegionError {
+    /// 登録できる領域の数の上限に達した
+    TooManyRegions,
+    /// 仮想アドレス範囲に十分な空きがない
+    OutOfVirtualMemory,
+    /// 既存の領域とガードページが重なる
+    Overlapping,
+    /// フレームの割り当てやマッピングに失敗した
+    MapFailed(MapToError<Size4KiB>),
+}
+
+/// 登録されている領域の表
+struct RegionTable {
+    regions: [Option<Region>; MAX_REGIONS],
+}
+
+static REGIONS: Mutex<RegionTable> = Mutex::new(RegionTable {
+    regions: [None; MAX_REGIONS],
+});
+
+impl RegionTable {
+    fn insert(&mut self, region: Region) -> Result<(), RegionError> {
+        let reserved = region.reserved();
+        if self.iter().any(|r| overlaps(&r.reserved(), &reserved)) {
+            return Err(RegionError::Overlapping);
+        }
+        let slot = self
+            .regions
+            .iter_mut()
+            .find(|r| r.is_none())
+            .ok_or(RegionError::TooManyRegions)?;
+        *slot = Some(region);
+        Ok(())
+    }
+
+    /// `area`の中から，ガードページも含めて`pages + 2`ページ分の空きを探す(first fit)
+    fn find_free(&self, area: &Range<u64>, pages: u64) -> Option<Range<u64>> {
+        let reserved_size = (pages + 2) * PAGE_SIZE;
+        let mut candidate = area.start..area.start + reserved_size;
+        'search: while candidate.end <= area.end {
+            for region in self.iter() {
+                let reserved = region.reserved();
+                if overlaps(&reserved, &candidate) {
+                    candidate = reserved.end..reserved.end + reserved_size;
+                    continue 'search;
+                }
+            }
+            return Some(candidate);
+        }
+        None
+    }
+
+    fn remove(&mut self, region: &Region) {
+        if let Some(slot) = self.regions.iter_mut().find(|r| r.as_ref() == Some(region)) {
+            *slot = None;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter().flatten()
+    }
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// `area`から`pages`ページの領域を予約し，前後にガードページを残して登録する
+///
+/// フレームはまだマップしない
+pub(crate) fn reserve_in(
+    area: Range<u64>,
+    name: &'static str,
+    pages: u64,
+    lazy_flags: Option<PageTableFlags>,
+) -> Result<Region, RegionError> {
+    interrupts::without_interrupts(|| {
+        let mut table = REGIONS.lock();
+        let reserved = table
+            .find_free(&area, pages)
+            .ok_or(RegionError::OutOfVirtualMemory)?;
+        let region = Region {
+            name,
+            start: VirtAddr::new(reserved.start + PAGE_SIZE),
+            end: VirtAddr::new(reserved.end - PAGE_SIZE),
+            lazy_flags,
+        };
+        table.insert(region)?;
+        Ok(region)
+    })
+}
+
+/// `KERNEL_REGION_AREA`から`pages`ページの領域を確保し，新しいフレームを`flags`でマップする
+///
+/// 領域の前後のページはマップせずガードページとして残すので，
+/// はみ出したアクセスはページフォルトになり`guard_page_hit`で領域を特定できる
+/// 書き込み可能なら`NO_EXECUTE`も立てる
+pub fn allocate(
+    name: &'static str,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<Region, RegionError> {
+    allocate_in(KERNEL_REGION_AREA, name, pages, flags)
+}
+
+/// `area`から領域を確保し，1ページずつ新しいフレームを`flags`でマップする
+pub(crate) fn allocate_in(
+    area: Range<u64>,
+    name: &'static str,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<Region, RegionError> {
+    let region = reserve_in(area, name, pages, None)?;
+    let flags = w_xor_x(flags | PageTableFlags::PRESENT);
+    let mapped = with_kernel_memory(|kernel_memory| {
+        for page in region.pages() {
+            let result = kernel_memory
+                .frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)
+                .and_then(|frame| unsafe {
+                    kernel_memory.mapper.map_to(
+                        page,
+                        frame,
+                        flags,
+                        &mut kernel_memory.frame_allocator,
+                    )
+                });
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    // マップ済みのページを戻す
+                    for mapped_page in Page::range(region.pages().start, page) {
+                        unsafe { kernel_memory.unmap_page(mapped_page) }.unwrap();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    })
+    .unwrap_or(Err(MapToError::FrameAllocationFailed));
+
+    match mapped {
+        Ok(()) => Ok(region),
+        Err(err) => {
+            unregister(&region);
+            Err(RegionError::MapFailed(err))
+        }
+    }
+}
+
+/// カーネルスタック用の領域を確保する
+///
+/// スタックは下に伸びるので，`region.end()`をスタックポインタの初期値に使う
+pub fn allocate_stack(name: &'static str, pages: u64) -> Result<Region, RegionError> {
+    allocate(
+        name,
+        pages,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )
+}
+
+/// `KERNEL_REGION_AREA`から`pages`ページの領域を予約する
+///
+/// フレームはすぐには割り当てず，ページに最初にアクセスしたときのページフォルトで
+/// ゼロ埋めしたフレームを`flags`でマップする．書き込み可能なら`NO_EXECUTE`も立てる
+pub fn allocate_lazy(
+    name: &'static str,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<Region, RegionError> {
+    reserve_in(
+        KERNEL_REGION_AREA,
+        name,
+        pages,
+        Some(w_xor_x(flags | PageTableFlags::PRESENT)),
+    )
+}
+
+/// `allocate`・`allocate_lazy`で確保した領域のマッピングを外し，フレームを解放する
+///
+/// この関数はunsafeである：呼び出し元は，領域への参照が残っていないことを
+/// 保証しなければならない
+pub unsafe fn free(region: Region) {
+    with_kernel_memory(|kernel_memory| {
+        for page in region.pages() {
+            match kernel_memory.unmap_page(page) {
+                Ok(()) => {}
+                // 遅延割り当ての領域では，アクセスされなかったページはマップされていない
+                Err(UnmapError::PageNotMapped) if region.is_lazy() => {}
+                Err(err) => panic!("failed to unmap region page: {:?}", err),
+            }
+        }
+    })
+    .expect("kernel memory is not available");
+    unregister(&region);
+}
+
+/// 呼び出し元が自分でマップする範囲`[start, end)`を領域として登録する
+///
+/// 呼び出し元は前後のページをマップしないままにしておかなければならない
+pub fn register(name: &'static str, start: VirtAddr, end: VirtAddr) -> Result<Region, RegionError> {
+    let region = Region {
+        name,
+        start,
+        end,
+        lazy_flags: None,
+    };
+    interrupts::without_interrupts(|| REGIONS.lock().insert(region))?;
+    Ok(region)
+}
+
+/// 範囲`[start, end)`を遅延割り当ての領域として登録する
+///
+/// 範囲内のページはマップしないままにしておき，ページフォルトのときに`flags`でマップされる
+/// 書き込み可能なら`NO_EXECUTE`も立てる
+pub fn register_lazy(
+    name: &'static str,
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<Region, RegionError> {
+    let region = Region {
+        name,
+        start,
+        end,
+        lazy_flags: Some(w_xor_x(flags | PageTableFlags::PRESENT)),
+    };
+    interrupts::without_interrupts(|| REGIONS.lock().insert(region))?;
+    Ok(region)
+}
+
+/// `start`から始まる登録済みの領域を返す
+pub fn find(start: VirtAddr) -> Option<Region> {
+    interrupts::without_interrupts(|| {
+        let table = REGIONS.lock();
+        let region = table.iter().find(|region| region.start == start).copied();
+        region
+    })
+}
+
+/// 領域の登録を取り消す
+pub fn unregister(region: &Region) {
+    interrupts::without_interrupts(|| REGIONS.lock().remove(region));
+}
+
+/// `addr`が登録されている領域のガードページ内にあれば，その領域を返す
+///
+/// ページフォルトハンドラから呼ばれるので，表が使用中なら待たずに`None`を返す
+pub fn guard_page_hit(addr: VirtAddr) -> Option<GuardPageHit> {
+    let table = REGIONS.try_lock()?;
+    let addr_u64 = addr.as_u64();
+    let hit = table.iter().find_map(|region| {
+        let side = if (region.start.as_u64() - PAGE_SIZE..region.start.as_u64()).contains(&addr_u64)
+        {
+            GuardSide::Below
+        } else if (region.end.as_u64()..region.end.as_u64() + PAGE_SIZE).contains(&addr_u64) {
+            GuardSide::Above
+        } else {
+            return None;
+        };
+        Some(GuardPageHit {
+            region: *region,
+            side,
+            addr,
+        })
+    });
+    hit
+}
+
+/// 遅延割り当ての領域内で起きた，ページが存在しないことによるフォルトを処理する
+///
+/// ゼロ埋めしたフレームをマップできたら`true`を返す．領域外のアドレスや
+/// 保護違反によるフォルトの場合，またページテーブルが使用中の場合は`false`を返す
+pub fn handle_lazy_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let flags = match REGIONS.try_lock() {
+        Some(table) => table
+            .iter()
+            .find(|region| region.contains(addr))
+            .and_then(|region| region.lazy_flags),
+        None => None,
+    };
+    let flags = match flags {
+        Some(flags) => flags,
+        None => return false,
+    };
+
+    let page: Page = Page::containing_address(addr);
+    with_kernel_memory(|kernel_memory| {
+        let frame = match kernel_memory.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let frame_ptr: *mut u8 =
+            (kernel_memory.mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe { frame_ptr.write_bytes(0, PAGE_SIZE as usize) };
+        let result = unsafe {
+            kernel_memory
+                .mapper
+                .map_to(page, frame, flags, &mut kernel_memory.frame_allocator)
+        };
+        match result {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => {
+                unsafe { kernel_memory.frame_allocator.deallocate_frame(frame) };
+                false
+            }
+        }
+    })
+    .unwrap_or(false)
+}
@@ -0,0 +1,172 @@
+use super::with_kernel_memory;
+use crate::{println, serial_println};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+/// `MemoryRegionType`の種類の数(`region_kind`が最後の種類に返す番号 + 1)
+const REGION_KINDS: usize = region_kind(MemoryRegionType::UnknownBios(0)) + 1;
+
+/// 領域の種類を`0..REGION_KINDS`の番号にする
+///
+/// `UnknownUefi`と`UnknownBios`は，中の値によらずそれぞれ1つの種類として扱う
+const fn region_kind(region_type: MemoryRegionType) -> usize {
+    use MemoryRegionType::*;
+    match region_type {
+        Usable => 0,
+        InUse => 1,
+        Reserved => 2,
+        AcpiReclaimable => 3,
+        AcpiNvs => 4,
+        BadMemory => 5,
+        Kernel => 6,
+        KernelStack => 7,
+        PageTable => 8,
+        Bootloader => 9,
+        FrameZero => 10,
+        Empty => 11,
+        BootInfo => 12,
+        Package => 13,
+        UnknownUefi(_) => 14,
+        UnknownBios(_) => 15,
+    }
+}
+
+/// メモリマップ中の，ある種類の領域の合計
+///
+/// `UnknownUefi`・`UnknownBios`の領域は，最初に現れた領域の`region_type`にまとめる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionTypeTotal {
+    pub region_type: MemoryRegionType,
+    /// この種類の領域の数
+    pub regions: usize,
+    pub bytes: u64,
+}
+
+/// メモリマップを領域の種類ごとに集計したもの
+#[derive(Debug, Clone)]
+pub struct MemoryMapSummary {
+    totals: [Option<RegionTypeTotal>; REGION_KINDS],
+    /// 全ての領域の合計バイト数
+    pub total_bytes: u64,
+}
+
+impl MemoryMapSummary {
+    pub fn new(memory_map: &MemoryMap) -> Self {
+        let mut summary = MemoryMapSummary {
+            totals: [None; REGION_KINDS],
+            total_bytes: 0,
+        };
+        for region in memory_map.iter() {
+            let bytes = region.range.end_addr() - region.range.start_addr();
+            summary.total_bytes += bytes;
+            let slot = summary
+                .totals
+                .iter_mut()
+                .find(|t| {
+                    t.map_or(true, |t| {
+                        region_kind(t.region_type) == region_kind(region.region_type)
+                    })
+                })
+                .expect("more memory region kinds than REGION_KINDS");
+            let total = slot.get_or_insert(RegionTypeTotal {
+                region_type: region.region_type,
+                regions: 0,
+                bytes: 0,
+            });
+            total.regions += 1;
+            total.bytes += bytes;
+        }
+        summary
+    }
+
+    /// メモリマップに現れた順に，種類ごとの合計を返す
+    pub fn iter(&self) -> impl Iterator<Item = &RegionTypeTotal> {
+        self.totals.iter().flatten()
+    }
+
+    /// `region_type`の種類の領域の合計バイト数
+    pub fn bytes_of(&self, region_type: MemoryRegionType) -> u64 {
+        self.iter()
+            .find(|t| region_kind(t.region_type) == region_kind(region_type))
+            .map_or(0, |t| t.bytes)
+    }
+}
+
+/// 物理フレームの使用状況
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameUsage {
+    /// フレームアロケータが管理している(Usableだった)フレームの数
+    pub total: usize,
+    pub free: usize,
+}
+
+impl FrameUsage {
+    pub fn used(&self) -> usize {
+        self.total - self.free
+    }
+}
+
+/// 現在の物理フレームの使用状況を返す
+///
+/// `init_kernel_memory`の前，またはページテーブルが使用中の場合は`None`を返す
+pub fn frame_usage() -> Option<FrameUsage> {
+    with_kernel_memory(|kernel_memory| FrameUsage {
+        total: kernel_memory.frame_allocator.total_frames(),
+        free: kernel_memory.frame_allocator.free_frames(),
+    })
+}
+
+/// メモリマップの全ての領域をシリアルポートに，種類ごとの合計をシリアルポートとVGAに出力する
+pub fn print_memory_map(memory_map: &MemoryMap) {
+    serial_println!("physical memory map:");
+    for region in memory_map.iter() {
+        serial_println!(
+            "  {:#012x}-{:#012x} {:>8} KiB {:?}",
+            region.range.start_addr(),
+            region.range.end_addr(),
+            (region.range.end_addr() - region.range.start_addr()) / 1024,
+            region.region_type
+        );
+    }
+
+    let summary = MemoryMapSummary::new(memory_map);
+    for total in summary.iter() {
+        serial_println!(
+            "{:>10} KiB in {:>2} regions: {:?}",
+            total.bytes / 1024,
+            total.regions,
+            total.region_type
+        );
+        println!(
+            "{:>10} KiB in {:>2} regions: {:?}",
+            total.bytes / 1024,
+            total.regions,
+            total.region_type
+        );
+    }
+    let usable = summary.bytes_of(MemoryRegionType::Usable);
+    serial_println!(
+        "total {} MiB, usable {} MiB",
+        summary.total_bytes >> 20,
+        usable >> 20
+    );
+    println!(
+        "total {} MiB, usable {} MiB",
+        summary.total_bytes >> 20,
+        usable >> 20
+    );
+
+    if let Some(usage) = frame_usage() {
+        serial_println!(
+            "frames: {} used, {} free of {}",
+            usage.used(),
+            usage.free,
+            usage.total
+        );
+        println!(
+            "frames: {} used, {} free of {}",
+            usage.used(),
+            usage.free,
+            usage.total
+        );
+    }
+}
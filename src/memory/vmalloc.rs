@@ -0,0 +1,33 @@
+use super::region::{self, RegionError};
+use core::ops::Range;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// `vmalloc`で割り当てる仮想アドレス範囲(レベル4テーブルのエントリ1つ分)
+pub const VMALLOC_AREA: Range<u64> = 0x_6600_0000_0000..0x_6680_0000_0000;
+
+/// `vmalloc`で割り当てた範囲の名前(ガードページに触れたときの報告に使う)
+const VMALLOC_REGION_NAME: &str = "vmalloc";
+
+/// 仮想アドレスとしては連続した`size`バイトの領域を割り当て，先頭のアドレスを返す
+///
+/// ヒープに入らない大きなバッファ向け．フレームは1つずつ割り当ててマップするので，
+/// 物理的に連続している必要はない．前後はガードページになる
+/// 内容は初期化されない
+pub fn vmalloc(size: usize) -> Result<VirtAddr, RegionError> {
+    let pages = (size as u64 + 4095) / 4096;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let region = region::allocate_in(VMALLOC_AREA, VMALLOC_REGION_NAME, pages.max(1), flags)?;
+    Ok(region.start())
+}
+
+/// `vmalloc`で割り当てた領域のマッピングを外し，フレームを解放する
+///
+/// この関数はunsafeである：呼び出し元は`addr`が`vmalloc`の返したアドレスで，
+/// 領域への参照がもう残っていないことを保証しなければならない
+pub unsafe fn vfree(addr: VirtAddr) {
+    let region = region::find(addr)
+        .filter(|region| VMALLOC_AREA.contains(&region.start().as_u64()))
+        .expect("vfree: address was not returned by vmalloc");
+    region::free(region);
+}
@@ -66,6 +66,16 @@ impl Executor {
         }
     }
 
+    /// 全てのタスクが完了するまで実行する
+    pub fn run_until_complete(&mut self) {
+        while !self.tasks.is_empty() {
+            self.run_ready_tasks();
+            if !self.tasks.is_empty() {
+                self.sleep_if_idle();
+            }
+        }
+    }
+
     /// 永久にループする関数とかには!が返り値になるらしい
     /// i.e. ネットワーク・サーバとかプロセスを終了する関数
     pub fn run(&mut self) -> ! {
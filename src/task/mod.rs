@@ -9,6 +9,7 @@ use core::{
 pub mod executor;
 pub mod keyboard;
 pub mod simple_executor;
+pub mod timer;
 
 /// ピン留めされ，Heapに割り当てられ，空の出力を持つ
 /// 動的なfetureのnewtypeのラッパー
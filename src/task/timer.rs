@@ -0,0 +1,261 @@
+use crate::time;
+use conquer_once::spin::OnceCell;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::interrupts;
+
+/// 同時に待てるタイマの数
+pub const MAX_TIMERS: usize = 64;
+
+/// タイマホイールのバケット数
+///
+/// 期限のティック数を`WHEEL_SIZE`で割った余りのバケットに入れ，
+/// タイマ割り込みは毎回そのティックのバケットだけを調べる
+const WHEEL_SIZE: usize = 32;
+
+/// タイマの状態
+///
+/// 割り込みハンドラから触るのでヒープには置かず，固定長の表にする
+struct TimerSlot {
+    in_use: AtomicBool,
+    /// 解放のたびに増やし，バケットに残った古いエントリを見分ける
+    generation: AtomicUsize,
+    deadline: AtomicU64,
+    /// バケットに戻せずに外れてしまったので，pollで入れ直す必要がある
+    requeue: AtomicBool,
+    waker: AtomicWaker,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+static TIMERS: [TimerSlot; MAX_TIMERS] = {
+    const FREE: TimerSlot = TimerSlot {
+        in_use: AtomicBool::new(false),
+        generation: AtomicUsize::new(0),
+        deadline: AtomicU64::new(0),
+        requeue: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    };
+    [FREE; MAX_TIMERS]
+};
+
+/// バケットごとの，タイマの番号と世代を詰めた値のキュー
+///
+/// タスクが追加し，タイマ割り込みが取り出す(SCANCODE_QUEUEと同じくロックを使わない)
+static WHEEL: OnceCell<[ArrayQueue<usize>; WHEEL_SIZE]> = OnceCell::uninit();
+
+fn wheel() -> &'static [ArrayQueue<usize>; WHEEL_SIZE] {
+    // 割り込みハンドラの中ではなく，最初にタイマを登録するタスクが作る
+    let _ = WHEEL.try_init_once(|| [(); WHEEL_SIZE].map(|_| ArrayQueue::new(MAX_TIMERS * 2)));
+    WHEEL.try_get().expect("timer wheel is not initialized")
+}
+
+fn pack(index: usize, generation: usize) -> usize {
+    generation * MAX_TIMERS + index
+}
+
+fn unpack(entry: usize) -> (usize, usize) {
+    (entry % MAX_TIMERS, entry / MAX_TIMERS)
+}
+
+/// タイマ割り込みハンドラから呼び出され，`tick`で期限の来たタイマのタスクを起こす
+///
+/// 処理をBlockしたり，allocateしてはいけない
+pub(crate) fn expire_timers(tick: u64) {
+    let wheel = match WHEEL.try_get() {
+        Ok(wheel) => wheel,
+        Err(_) => return,
+    };
+    let bucket = &wheel[(tick % WHEEL_SIZE as u64) as usize];
+    // 期限がまだ先(ホイールの次の周以降)のエントリは同じバケットに戻すので，
+    // 最初に入っていた数だけ取り出す
+    for _ in 0..bucket.len() {
+        let entry = match bucket.pop() {
+            Ok(entry) => entry,
+            Err(_) => break,
+        };
+        let (index, generation) = unpack(entry);
+        let slot = &TIMERS[index];
+        if !slot.in_use.load(Ordering::Acquire)
+            || slot.generation.load(Ordering::Acquire) != generation
+        {
+            // 解放済みのタイマ
+            continue;
+        }
+        if slot.deadline.load(Ordering::Acquire) <= tick {
+            slot.waker.wake();
+        } else if bucket.push(entry).is_err() {
+            // 戻せなかったら印をつけて起こし，pollで入れ直してもらう
+            slot.requeue.store(true, Ordering::Release);
+            slot.waker.wake();
+        }
+    }
+}
+
+/// タイマ`index`を`deadline`のバケットに入れる．バケットがいっぱいなら`false`を返す
+fn enqueue(index: usize, generation: usize, deadline: u64) -> bool {
+    let bucket = &wheel()[(deadline % WHEEL_SIZE as u64) as usize];
+    // ArrayQueueはpushの途中で割り込まれると，割り込みハンドラのpopが終わらなくなる
+    interrupts::without_interrupts(|| bucket.push(pack(index, generation))).is_ok()
+}
+
+/// 待機中のタイマの数
+pub fn active_timers() -> usize {
+    TIMERS
+        .iter()
+        .filter(|slot| slot.in_use.load(Ordering::Relaxed))
+        .count()
+}
+
+/// 確保したタイマの番号と世代
+#[derive(Debug)]
+struct TimerHandle {
+    index: usize,
+    generation: usize,
+}
+
+impl TimerHandle {
+    /// 空いているタイマを確保し，`deadline`のバケットに入れる
+    fn register(deadline: u64) -> Option<TimerHandle> {
+        let index = TIMERS.iter().position(|slot| {
+            slot.in_use
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        })?;
+        let slot = &TIMERS[index];
+        slot.deadline.store(deadline, Ordering::Release);
+        let handle = TimerHandle {
+            index,
+            generation: slot.generation.load(Ordering::Acquire),
+        };
+        // 入れられなかった場合はhandleのdropでタイマを解放する
+        if !enqueue(index, handle.generation, deadline) {
+            return None;
+        }
+        Some(handle)
+    }
+
+    /// タイマ割り込みがバケットに戻せなかったタイマを，もう一度バケットに入れる
+    ///
+    /// 入れ直す必要がないか，入れられたら`true`を返す
+    fn requeue(&self, deadline: u64) -> bool {
+        let slot = self.slot();
+        if !slot.requeue.swap(false, Ordering::AcqRel) {
+            return true;
+        }
+        if enqueue(self.index, self.generation, deadline) {
+            return true;
+        }
+        slot.requeue.store(true, Ordering::Release);
+        false
+    }
+
+    fn slot(&self) -> &'static TimerSlot {
+        &TIMERS[self.index]
+    }
+}
+
+impl Drop for TimerHandle {
+    fn drop(&mut self) {
+        let slot = self.slot();
+        slot.generation.fetch_add(1, Ordering::AcqRel);
+        slot.requeue.store(false, Ordering::Release);
+        slot.waker.take();
+        slot.in_use.store(false, Ordering::Release);
+    }
+}
+
+/// 指定したティックまで待つFuture
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: u64,
+    timer: Option<TimerHandle>,
+}
+
+/// `duration`だけ待つ
+///
+/// タイマ割り込みの周期(約1ms)単位で切り上げる
+/// 期限が`u64`のティック数に収まらなければ，`u64::MAX`で止める(実質的に起きない)
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: time::ticks().saturating_add(time::duration_to_ticks(duration)),
+        timer: None,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if time::ticks() >= self.deadline {
+            self.timer = None;
+            return Poll::Ready(());
+        }
+        if self.timer.is_none() {
+            self.timer = TimerHandle::register(self.deadline);
+        }
+        match &self.timer {
+            Some(timer) => {
+                timer.slot().waker.register(cx.waker());
+                // バケットに戻せなかったタイマは入れ直し，それもできなければすぐにもう一度pollしてもらう
+                if !timer.requeue(self.deadline) {
+                    cx.waker().wake_by_ref();
+                }
+            }
+            // タイマが足りなければ，すぐにもう一度pollしてもらう
+            None => cx.waker().wake_by_ref(),
+        }
+
+        // 登録している間に期限が来たかもしれない
+        if time::ticks() >= self.deadline {
+            self.timer = None;
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+/// `timeout`で期限までに`future`が完了しなかったことを表す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// 期限付きで`future`を待つFuture
+#[derive(Debug)]
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// `future`を最大`duration`だけ待つ
+///
+/// 期限までに完了すればその結果を，完了しなければ`Err(Elapsed)`を返す
+/// 期限が来た時点で`future`はdropされる
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // `future`はピン留めされたまま動かさず，`sleep`はUnpinなので普通に借用する
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
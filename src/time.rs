@@ -0,0 +1,105 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+/// PIT(8253/8254)の入力クロックの周波数(Hz)
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// タイマ割り込みの目標の周波数(Hz)
+pub const TICKS_PER_SECOND: u64 = 1000;
+
+/// PITのチャンネル0に設定する分周比
+/// 割り切れないので，実際の周波数は`PIT_FREQUENCY / PIT_DIVISOR`になる
+const PIT_DIVISOR: u64 = PIT_FREQUENCY / TICKS_PER_SECOND;
+
+/// 1ティックのナノ秒数(端数を含めて計算するため`PIT_FREQUENCY`で割る前の値)
+const NANOS_PER_TICK_NUMERATOR: u64 = PIT_DIVISOR * 1_000_000_000;
+
+/// 起動してからのタイマ割り込みの回数
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// PITのチャンネル0を`TICKS_PER_SECOND`の周期でIRQ0を起こすように設定する
+pub fn init() {
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+    interrupts::without_interrupts(|| unsafe {
+        // チャンネル0，下位・上位バイトの順にアクセス，モード2(レートジェネレータ)，2進数
+        command.write(0b0011_0100);
+        channel_0.write(PIT_DIVISOR as u8);
+        channel_0.write((PIT_DIVISOR >> 8) as u8);
+    });
+}
+
+/// タイマ割り込みのハンドラから呼ばれ，ティックを1つ進める
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 起動してからのティック数
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// 起動してからの経過時間
+///
+/// タイマ割り込みの周期(約1ms)の精度で，単調に増加する
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+/// ティック数を時間に変換する
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos =
+        u128::from(ticks) * u128::from(NANOS_PER_TICK_NUMERATOR) / u128::from(PIT_FREQUENCY);
+    Duration::from_nanos(nanos as u64)
+}
+
+/// 時間をティック数に変換する(切り上げ)
+///
+/// `u64`に収まらない長さは`u64::MAX`にする
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let numerator = u128::from(NANOS_PER_TICK_NUMERATOR);
+    let ticks = (duration.as_nanos() * u128::from(PIT_FREQUENCY) + numerator - 1) / numerator;
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+/// `ticks`回のタイマ割り込みが起きるまでhltで待つ
+///
+/// 割り込みが有効になっていなければ戻らない
+/// 待つティック数が`u64`に収まらなければ，`u64::MAX`まで待つ(実質的に戻らない)
+pub fn wait_ticks(ticks: u64) {
+    let target = self::ticks().saturating_add(ticks);
+    while self::ticks() < target {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_ticks_advance() {
+    let before = ticks();
+    wait_ticks(10);
+    assert!(ticks() >= before + 10);
+}
+
+#[test_case]
+fn test_uptime_advances_with_ticks() {
+    let before = uptime();
+    wait_ticks(20);
+    let elapsed = uptime() - before;
+    assert!(elapsed >= Duration::from_millis(19));
+    assert!(elapsed < Duration::from_secs(1));
+}
+
+#[test_case]
+fn test_tick_conversion() {
+    assert_eq!(duration_to_ticks(Duration::from_secs(0)), 0);
+    let one_second = duration_to_ticks(Duration::from_secs(1));
+    assert!((TICKS_PER_SECOND - 1..=TICKS_PER_SECOND + 1).contains(&one_second));
+    assert!(ticks_to_duration(one_second) >= Duration::from_secs(1));
+}
+
+#[test_case]
+fn test_huge_duration_saturates() {
+    assert_eq!(duration_to_ticks(Duration::MAX), u64::MAX);
+}
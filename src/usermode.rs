@@ -0,0 +1,90 @@
+use crate::gdt;
+use core::arch::global_asm;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+/// ユーザモードのコードがカーネルに戻るための割り込みのベクタ
+///
+/// RAXに終了コードを入れて`int 0x80`を実行する
+pub const EXIT_VECTOR: u8 = 0x80;
+
+// usermode_enter(entry, user_stack, user_cs, user_ss) -> u64
+//
+// 呼び出し元のレジスタを保存してから，iretqでring 3の`entry`に移る
+// ユーザモードから`int 0x80`が実行されると`usermode_exit`に入るので，
+// 保存しておいたスタックに戻し，RAX(終了コード)をそのまま返り値にする
+global_asm!(
+    ".pushsection .text.usermode, \"ax\"",
+    ".global usermode_enter",
+    "usermode_enter:",
+    "    push rbx",
+    "    push rbp",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    pushfq",
+    "    mov [rip + usermode_kernel_rsp], rsp",
+    "    mov word ptr [rip + usermode_kernel_ss], ss",
+    // iretqで読まれるフレーム: SS, RSP, RFLAGS, CS, RIP
+    "    push rcx",
+    "    push rsi",
+    "    push 0x202", // 割り込みは有効にしておく
+    "    push rdx",
+    "    push rdi",
+    "    iretq",
+    ".global usermode_exit",
+    "usermode_exit:",
+    // ring 3から入るとSSはヌルセレクタになるので，元に戻す
+    "    mov ss, word ptr [rip + usermode_kernel_ss]",
+    "    mov rsp, [rip + usermode_kernel_rsp]",
+    "    popfq",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbp",
+    "    pop rbx",
+    "    ret",
+    ".popsection",
+    ".pushsection .bss.usermode, \"aw\", @nobits",
+    ".balign 8",
+    "usermode_kernel_rsp:",
+    "    .zero 8",
+    "usermode_kernel_ss:",
+    "    .zero 2",
+    ".popsection",
+);
+
+extern "C" {
+    fn usermode_enter(entry: u64, user_stack: u64, user_cs: u64, user_ss: u64) -> u64;
+    fn usermode_exit();
+}
+
+/// `EXIT_VECTOR`をIDTに登録する
+///
+/// ユーザモードから`int`で呼べるよう，DPLは3にする
+pub(crate) fn set_exit_handler(idt: &mut InterruptDescriptorTable) {
+    let exit = VirtAddr::new(usermode_exit as unsafe extern "C" fn() as usize as u64);
+    unsafe { idt[usize::from(EXIT_VECTOR)].set_handler_addr(exit) }
+        .set_privilege_level(PrivilegeLevel::Ring3);
+}
+
+/// `entry`からユーザモード(ring 3)で実行し，`EXIT_VECTOR`の割り込みで戻ってくるのを待つ
+///
+/// スタックポインタの初期値は`stack_top`になる．戻ってきたときのRAXの値を返す
+/// ユーザモードの間に起きた割り込み・例外は`privilege_stack_table[0]`のスタックで処理される
+/// 保存先は1つしかないので，入れ子にはできない
+///
+/// この関数はunsafeである：呼び出し元は`entry`のコードと`stack_top`の下のスタックが
+/// ユーザからアクセスできるページにマップされていて，そのコードが最後に
+/// `EXIT_VECTOR`の割り込みを起こすことを保証しなければならない
+pub unsafe fn run_user_mode(entry: VirtAddr, stack_top: VirtAddr) -> u64 {
+    let selectors = gdt::user_selectors();
+    usermode_enter(
+        entry.as_u64(),
+        stack_top.as_u64(),
+        u64::from(selectors.code.0),
+        u64::from(selectors.data.0),
+    )
+}
@@ -0,0 +1,136 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use blog_os::memory::address_space::USER_SPACE_START;
+use blog_os::memory::vmalloc::{self, VMALLOC_AREA};
+use blog_os::memory::{self, AddressSpace, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Translate};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames()).unwrap()
+}
+
+fn user_page() -> Page {
+    Page::containing_address(VirtAddr::new(USER_SPACE_START + 0x1000))
+}
+
+#[test_case]
+fn user_pages_are_private_to_address_space() {
+    let kernel_frame: PhysFrame = Cr3::read().0;
+    let heap_value = Box::new(42u64);
+
+    let mut address_space = AddressSpace::new().unwrap();
+    address_space
+        .map_user_page(user_page(), PageTableFlags::WRITABLE)
+        .unwrap();
+    let user_ptr: *mut u64 = user_page().start_address().as_mut_ptr();
+
+    unsafe { address_space.activate() };
+    assert_eq!(Cr3::read().0, address_space.level_4_frame());
+    unsafe {
+        // 新しいユーザページはゼロで埋められている
+        assert_eq!(user_ptr.read_volatile(), 0);
+        user_ptr.write_volatile(7);
+        assert_eq!(user_ptr.read_volatile(), 7);
+    }
+    // カーネルのマッピングは共有されている
+    assert_eq!(*heap_value, 42);
+
+    unsafe { memory::address_space::activate_kernel() };
+    assert_eq!(Cr3::read().0, kernel_frame);
+    // カーネルのアドレス空間にはユーザページがマップされていない
+    let translated =
+        memory::with_kernel_memory(|m| m.mapper.translate_addr(user_page().start_address()));
+    assert_eq!(translated, Some(None));
+}
+
+#[test_case]
+fn dropping_address_space_frees_frames() {
+    let before = free_frames();
+    {
+        let mut address_space = AddressSpace::new().unwrap();
+        for i in 0..4 {
+            let page = user_page() + i * 512;
+            address_space
+                .map_user_page(page, PageTableFlags::WRITABLE)
+                .unwrap();
+        }
+        assert!(free_frames() < before);
+    }
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn unmap_user_page_frees_frame() {
+    let mut address_space = AddressSpace::new().unwrap();
+    address_space
+        .map_user_page(user_page(), PageTableFlags::WRITABLE)
+        .unwrap();
+    let before = free_frames();
+    address_space.unmap_user_page(user_page()).unwrap();
+    assert_eq!(free_frames(), before + 1);
+}
+
+#[test_case]
+fn writable_user_pages_are_not_executable() {
+    let mut address_space = AddressSpace::new().unwrap();
+    address_space
+        .map_user_page(user_page(), PageTableFlags::WRITABLE)
+        .unwrap();
+    unsafe { address_space.activate() };
+    let found = memory::find_writable_executable();
+    unsafe { memory::address_space::activate_kernel() };
+    if let Some(range) = found {
+        panic!("writable and executable mapping: {}", range);
+    }
+}
+
+#[test_case]
+fn kernel_mappings_created_while_user_space_is_active_are_visible() {
+    // vmallocの範囲にはまだレベル4のエントリがなく，カーネルのテーブルにだけ作られる
+    let p4_index = VirtAddr::new(VMALLOC_AREA.start).p4_index();
+    let unused = memory::with_kernel_memory(|m| m.mapper.level_4_table()[p4_index].is_unused());
+    assert_eq!(unused, Some(true));
+
+    let address_space = AddressSpace::new().unwrap();
+    unsafe { address_space.activate() };
+    let addr = vmalloc::vmalloc(4096).unwrap();
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(9);
+        assert_eq!(ptr.read_volatile(), 9);
+        memory::address_space::activate_kernel();
+        vmalloc::vfree(addr);
+    }
+}
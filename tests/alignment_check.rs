@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+
+use blog_os::fmt_buffer::FmtBuffer;
+use blog_os::gdt;
+use blog_os::memory::address_space::{self, USER_SPACE_START};
+use blog_os::memory::{self, AddressSpace, BootInfoFrameAllocator};
+use blog_os::usermode;
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// RFLAGSのACを立ててから，8バイト境界にそろっていない場所を読む
+global_asm!(
+    ".pushsection .rodata.user_programs, \"a\"",
+    ".global user_misaligned_load",
+    "user_misaligned_load:",
+    "    pushfq",
+    "    or dword ptr [rsp], 0x40000",
+    "    popfq",
+    "    mov rax, qword ptr [rsp - 7]",
+    "    int 0x80",
+    ".global user_misaligned_load_end",
+    "user_misaligned_load_end:",
+    ".popsection",
+);
+
+extern "C" {
+    static user_misaligned_load: u8;
+    static user_misaligned_load_end: u8;
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    serial_print!("alignment_check::user_misaligned_load...\t");
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    // AMが立っていないと，ring 3でACを立ててもアラインメントは検査されない
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::ALIGNMENT_MASK)) };
+    let code = unsafe {
+        let start = &user_misaligned_load as *const u8;
+        let end = &user_misaligned_load_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+
+    let code_page: Page = Page::containing_address(VirtAddr::new(USER_SPACE_START));
+    let stack_page = code_page + 16;
+    let mut address_space = AddressSpace::new().unwrap();
+    let code_frame = address_space
+        .map_user_page(code_page, PageTableFlags::empty())
+        .unwrap();
+    address_space
+        .map_user_page(
+            stack_page,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .unwrap();
+    unsafe {
+        let dst: *mut u8 = (phys_mem_offset + code_frame.start_address().as_u64()).as_mut_ptr();
+        dst.copy_from_nonoverlapping(code.as_ptr(), code.len());
+    }
+
+    unsafe { address_space.activate() };
+    let stack_top = stack_page.start_address() + 4096u64;
+    unsafe { usermode::run_user_mode(code_page.start_address(), stack_top) };
+    unsafe { address_space::activate_kernel() };
+
+    panic!("Execution continued after misaligned load");
+}
+
+/// ユーザモードで#ACが起きると，カーネルの例外ハンドラがクラッシュレポートを出してpanicする
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = FmtBuffer::<4096>::new();
+    let _ = write!(message, "{}", info);
+    // 例外はユーザのコードセグメントで起きている
+    let mut user_code = FmtBuffer::<64>::new();
+    let _ = write!(user_code, "code_segment: {},", gdt::user_selectors().code.0);
+
+    let message = message.as_str();
+    if message.contains("ALIGNMENT CHECK (#AC, vector 17)") && message.contains(user_code.as_str())
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        blog_os::hlt_loop();
+    }
+    blog_os::test_panic_handler(info);
+}
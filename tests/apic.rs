@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::acpi;
+use blog_os::interrupts::{self, apic, ioapic};
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+    interrupts::init_apic().expect("APIC initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_describes_interrupt_controllers() {
+    let info = acpi::find_apic_info().unwrap();
+    assert!(info.io_apics().any(|io_apic| io_apic.gsi_base == 0));
+    assert!(info.local_apic_ids.iter().flatten().count() >= 1);
+}
+
+#[test_case]
+fn local_apic_is_enabled() {
+    let local_apic = apic::local_apic().expect("local APIC is not enabled");
+    let info = acpi::find_apic_info().unwrap();
+    assert!(info.local_apic_ids.contains(&Some(local_apic.id())));
+}
+
+#[test_case]
+fn legacy_pics_are_masked() {
+    let masks = unsafe { interrupts::PICS.lock().read_masks() };
+    assert_eq!(masks, [0xff, 0xff]);
+}
+
+#[test_case]
+fn only_handled_irqs_are_unmasked() {
+    assert_eq!(ioapic::is_irq_masked(0), Some(false));
+    assert_eq!(ioapic::is_irq_masked(1), Some(false));
+    assert_eq!(ioapic::is_irq_masked(4), Some(true));
+}
+
+#[test_case]
+fn timer_interrupts_arrive_through_io_apic() {
+    // EOIが届いていなければ，次のタイマ割り込みが来ずに止まる
+    for _ in 0..10 {
+        x86_64::instructions::hlt();
+    }
+}
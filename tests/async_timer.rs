@@ -0,0 +1,154 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use blog_os::task::executor::Executor;
+use blog_os::task::timer::{self, sleep, timeout, Elapsed};
+use blog_os::task::Task;
+use blog_os::time;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn sleep_waits_for_duration() {
+    static ELAPSED_MS: AtomicU64 = AtomicU64::new(0);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let start = time::uptime();
+        sleep(Duration::from_millis(30)).await;
+        ELAPSED_MS.store(
+            (time::uptime() - start).as_millis() as u64,
+            Ordering::SeqCst,
+        );
+    }));
+    executor.run_until_complete();
+
+    let elapsed = ELAPSED_MS.load(Ordering::SeqCst);
+    assert!(elapsed >= 30, "woke after {} ms", elapsed);
+    assert!(elapsed < 1000, "woke after {} ms", elapsed);
+    assert_eq!(timer::active_timers(), 0);
+}
+
+#[test_case]
+fn sleeps_finish_in_deadline_order() {
+    static ORDER: [AtomicUsize; 3] = [
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+    ];
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    for (id, ms) in [(0usize, 50u64), (1, 10), (2, 30)] {
+        executor.spawn(Task::new(async move {
+            sleep(Duration::from_millis(ms)).await;
+            ORDER[FINISHED.fetch_add(1, Ordering::SeqCst)].store(id, Ordering::SeqCst);
+        }));
+    }
+    executor.run_until_complete();
+
+    let order = [0, 1, 2].map(|i| ORDER[i].load(Ordering::SeqCst));
+    assert_eq!(order, [1, 2, 0]);
+}
+
+#[test_case]
+fn long_sleep_spans_wheel_rotations() {
+    let mut executor = Executor::new();
+    let start = time::ticks();
+    executor.spawn(Task::new(async {
+        sleep(Duration::from_millis(100)).await;
+    }));
+    executor.run_until_complete();
+    assert!(time::ticks() - start >= 100);
+}
+
+#[test_case]
+fn timeout_returns_output_when_future_is_fast() {
+    static RESULT: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let result = timeout(Duration::from_millis(100), async {
+            sleep(Duration::from_millis(5)).await;
+            42
+        })
+        .await;
+        RESULT.store(result.unwrap(), Ordering::SeqCst);
+    }));
+    executor.run_until_complete();
+    assert_eq!(RESULT.load(Ordering::SeqCst), 42);
+    assert_eq!(timer::active_timers(), 0);
+}
+
+#[test_case]
+fn timeout_elapses_for_slow_future() {
+    static ELAPSED_MS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let start = time::uptime();
+        let result = timeout(Duration::from_millis(20), sleep(Duration::from_secs(10))).await;
+        assert_eq!(result, Err(Elapsed));
+        ELAPSED_MS.store(
+            (time::uptime() - start).as_millis() as u64,
+            Ordering::SeqCst,
+        );
+    }));
+    executor.run_until_complete();
+
+    let elapsed = ELAPSED_MS.load(Ordering::SeqCst);
+    assert!(
+        (20..1000).contains(&elapsed),
+        "timed out after {} ms",
+        elapsed
+    );
+    // 期限が来た方のsleepはdropされ，タイマも解放されている
+    assert_eq!(timer::active_timers(), 0);
+}
+
+#[test_case]
+fn huge_sleep_stays_pending() {
+    static RESULT: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        // 期限の計算があふれず，いつまでも終わらないsleepになる
+        let result = timeout(Duration::from_millis(10), sleep(Duration::MAX)).await;
+        assert_eq!(result, Err(Elapsed));
+        let result = timeout(Duration::MAX, async { 7 }).await;
+        RESULT.store(result.unwrap(), Ordering::SeqCst);
+    }));
+    executor.run_until_complete();
+    assert_eq!(RESULT.load(Ordering::SeqCst), 7);
+    assert_eq!(timer::active_timers(), 0);
+}
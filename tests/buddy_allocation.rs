@@ -0,0 +1,98 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn blocks_are_aligned_to_their_size() {
+    memory::with_kernel_memory(|kernel_memory| {
+        let buddy = &mut kernel_memory.frame_allocator;
+        for order in 0..=4 {
+            let block = buddy.allocate(order).unwrap();
+            let block_size = 4096u64 << order;
+            assert_eq!(block.start_address().as_u64() % block_size, 0);
+            unsafe { buddy.deallocate(block, order) };
+        }
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn blocks_do_not_overlap() {
+    memory::with_kernel_memory(|kernel_memory| {
+        let buddy = &mut kernel_memory.frame_allocator;
+        let a = buddy.allocate(3).unwrap().start_address().as_u64();
+        let b = buddy.allocate(3).unwrap().start_address().as_u64();
+        let size = 4096 << 3;
+        assert!(a + size <= b || b + size <= a);
+        unsafe {
+            buddy.deallocate(PhysFrame::containing_address(PhysAddr::new(a)), 3);
+            buddy.deallocate(PhysFrame::containing_address(PhysAddr::new(b)), 3);
+        }
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn freed_buddies_are_merged() {
+    memory::with_kernel_memory(|kernel_memory| {
+        let buddy = &mut kernel_memory.frame_allocator;
+        let free_before = buddy.free_frames();
+        // オーダー6のブロックを前後2つのオーダー5に分け，後ろ半分は割り当てたままにしておく
+        // 前半分をさらに2つのオーダー4に分けて解放すると，前半分だけがオーダー5に結合される
+        let block = buddy.allocate(6).unwrap();
+        let upper = block + 32;
+        let halves = [block, block + 16];
+        unsafe { buddy.deallocate(halves[0], 4) };
+        assert_eq!(buddy.free_block_order(halves[0]), Some(4));
+        unsafe { buddy.deallocate(halves[1], 4) };
+        assert_eq!(buddy.free_block_order(halves[1]), None);
+        assert_eq!(buddy.free_block_order(block), Some(5));
+
+        unsafe { buddy.deallocate(upper, 5) };
+        assert_eq!(buddy.free_frames(), free_before);
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn huge_frames() {
+    memory::with_kernel_memory(|kernel_memory| {
+        let buddy = &mut kernel_memory.frame_allocator;
+        let free_before = buddy.free_frames();
+        let frame: PhysFrame<Size2MiB> = buddy.allocate_frame().unwrap();
+        assert_eq!(frame.start_address().as_u64() % (2 * 1024 * 1024), 0);
+        assert_eq!(buddy.free_frames(), free_before - 512);
+        unsafe { buddy.deallocate_frame(frame) };
+        assert_eq!(buddy.free_frames(), free_before);
+    })
+    .unwrap();
+}
@@ -0,0 +1,155 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::cow::{self, COPY_ON_WRITE};
+use blog_os::memory::region::{self, Region};
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Translate};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames()).unwrap()
+}
+
+fn mapping(page: Page) -> (PhysFrame, PageTableFlags) {
+    let result = memory::with_kernel_memory(|m| m.mapper.translate(page.start_address()));
+    match result.unwrap() {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (frame, flags),
+        other => panic!("page is not mapped: {:?}", other),
+    }
+}
+
+/// 書き込み可能なページと，共有先にする未マップのページを用意する
+fn pages() -> (Region, Region) {
+    let src = region::allocate("cow source", 1, PageTableFlags::WRITABLE).unwrap();
+    let dst = region::allocate_lazy("cow destination", 1, PageTableFlags::WRITABLE).unwrap();
+    (src, dst)
+}
+
+fn release(src: Region, dst: Region) {
+    unsafe {
+        cow::unmap_shared(src.pages().start).unwrap();
+        cow::unmap_shared(dst.pages().start).unwrap();
+        region::unregister(&src);
+        region::free(dst);
+    }
+}
+
+#[test_case]
+fn shared_page_has_same_contents() {
+    let (src, dst) = pages();
+    unsafe { src.start().as_mut_ptr::<u64>().write_volatile(0xc0ffee) };
+
+    let frame = cow::share_copy_on_write(src.pages().start, dst.pages().start).unwrap();
+    assert_eq!(cow::ref_count(frame), 2);
+    assert_eq!(
+        unsafe { dst.start().as_ptr::<u64>().read_volatile() },
+        0xc0ffee
+    );
+
+    let (src_frame, src_flags) = mapping(src.pages().start);
+    let (dst_frame, dst_flags) = mapping(dst.pages().start);
+    assert_eq!(src_frame, dst_frame);
+    assert!(!src_flags.contains(PageTableFlags::WRITABLE));
+    assert!(dst_flags.contains(COPY_ON_WRITE));
+    release(src, dst);
+}
+
+#[test_case]
+fn write_copies_frame() {
+    let (src, dst) = pages();
+    unsafe { src.start().as_mut_ptr::<u64>().write_volatile(1) };
+    let frame = cow::share_copy_on_write(src.pages().start, dst.pages().start).unwrap();
+
+    unsafe { dst.start().as_mut_ptr::<u64>().write_volatile(2) };
+    assert_eq!(unsafe { src.start().as_ptr::<u64>().read_volatile() }, 1);
+    assert_eq!(unsafe { dst.start().as_ptr::<u64>().read_volatile() }, 2);
+
+    let (dst_frame, dst_flags) = mapping(dst.pages().start);
+    assert_ne!(dst_frame, frame);
+    assert!(dst_flags.contains(PageTableFlags::WRITABLE));
+    assert!(!dst_flags.contains(COPY_ON_WRITE));
+    assert_eq!(cow::ref_count(frame), 1);
+    release(src, dst);
+}
+
+#[test_case]
+fn last_reference_is_made_writable_in_place() {
+    let (src, dst) = pages();
+    let frame = cow::share_copy_on_write(src.pages().start, dst.pages().start).unwrap();
+    unsafe { dst.start().as_mut_ptr::<u64>().write_volatile(2) };
+
+    // 共有が解けたので，元のページへの書き込みではコピーしない
+    unsafe { src.start().as_mut_ptr::<u64>().write_volatile(3) };
+    let (src_frame, src_flags) = mapping(src.pages().start);
+    assert_eq!(src_frame, frame);
+    assert!(src_flags.contains(PageTableFlags::WRITABLE));
+    release(src, dst);
+}
+
+#[test_case]
+fn frame_is_freed_with_last_mapping() {
+    let (src, dst) = pages();
+    unsafe { src.start().as_mut_ptr::<u64>().write_volatile(7) };
+    let before = free_frames();
+    let frame = cow::share_copy_on_write(src.pages().start, dst.pages().start).unwrap();
+
+    unsafe { cow::unmap_shared(src.pages().start).unwrap() };
+    assert_eq!(cow::ref_count(frame), 1);
+    assert_eq!(unsafe { dst.start().as_ptr::<u64>().read_volatile() }, 7);
+
+    let before_last = free_frames();
+    unsafe { cow::unmap_shared(dst.pages().start).unwrap() };
+    assert_eq!(free_frames(), before_last + 1);
+    assert!(free_frames() >= before);
+    unsafe {
+        region::unregister(&src);
+        region::free(dst);
+    }
+}
+
+#[test_case]
+fn sharing_and_copying_do_not_use_heap() {
+    use blog_os::allocator;
+
+    let (src, dst) = pages();
+    // ページフォルトハンドラはヒープのロックを取れないことがあるので，参照カウントもヒープに置かない
+    let before = allocator::stats();
+    cow::share_copy_on_write(src.pages().start, dst.pages().start).unwrap();
+    unsafe { dst.start().as_mut_ptr::<u64>().write_volatile(1) };
+    let after = allocator::stats();
+    assert_eq!(after.allocations, before.allocations);
+    assert_eq!(after.deallocations, before.deallocations);
+    release(src, dst);
+}
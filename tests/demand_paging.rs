@@ -0,0 +1,99 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::region;
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{Page, PageTableFlags, Translate};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames()).unwrap()
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::with_kernel_memory(|m| m.mapper.translate_addr(addr).is_some()).unwrap()
+}
+
+#[test_case]
+fn lazy_region_is_not_mapped_up_front() {
+    let before = free_frames();
+    let region = region::allocate_lazy("lazy region", 16, PageTableFlags::WRITABLE).unwrap();
+    assert_eq!(free_frames(), before);
+    assert!(region.pages().all(|page| !is_mapped(page.start_address())));
+    unsafe { region::free(region) };
+}
+
+#[test_case]
+fn fault_maps_zeroed_frame() {
+    let region = region::allocate_lazy("lazy region", 4, PageTableFlags::WRITABLE).unwrap();
+    let ptr = region.start().as_ptr::<u64>();
+    for i in 0..512 {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, 0);
+    }
+    assert!(is_mapped(region.start()));
+    assert!(!is_mapped(region.start() + 4096u64));
+    unsafe { region::free(region) };
+}
+
+#[test_case]
+fn writes_survive_after_fault() {
+    let region = region::allocate_lazy("lazy region", 8, PageTableFlags::WRITABLE).unwrap();
+    let ptr = region.start().as_mut_ptr::<u64>();
+    let len = region.size() as usize / 8;
+    for i in (0..len).step_by(64) {
+        unsafe { ptr.add(i).write_volatile(i as u64) };
+    }
+    for i in (0..len).step_by(64) {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, i as u64);
+    }
+    unsafe { region::free(region) };
+}
+
+#[test_case]
+fn only_touched_pages_use_frames() {
+    let region = region::allocate_lazy("sparse region", 64, PageTableFlags::WRITABLE).unwrap();
+    let before = free_frames();
+    let pages = region.pages();
+    let last: Page = Page::containing_address(region.end() - 1u64);
+    unsafe {
+        pages
+            .start
+            .start_address()
+            .as_mut_ptr::<u8>()
+            .write_volatile(1);
+        last.start_address().as_mut_ptr::<u8>().write_volatile(1);
+    }
+    // ページテーブル用のフレームが必要になることがある
+    assert!(before - free_frames() >= 2);
+    assert!(before - free_frames() <= 2 + 3);
+
+    let after_touch = free_frames();
+    unsafe { region::free(region) };
+    assert_eq!(free_frames(), after_touch + 2);
+}
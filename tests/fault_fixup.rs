@@ -0,0 +1,128 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::interrupts::{register_fixup, search_fixup, unregister_fixup, Fixup, FixupError};
+use blog_os::memory::region;
+use blog_os::memory::{self, BootInfoFrameAllocator, CopyFault};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// どこにもマップされていないアドレス
+const UNMAPPED: u64 = 0xdeadbeaf000;
+/// 非canonicalなアドレス．アクセスするとpage faultではなく#GPになる
+const NON_CANONICAL: u64 = 0x8000_0000_0000_0000;
+
+#[test_case]
+fn copy_from_mapped_memory() {
+    let src = [1u8, 2, 3, 4, 5, 6, 7, 8];
+    let mut dst = [0u8; 8];
+    assert_eq!(
+        unsafe { memory::copy_from_checked(&mut dst, src.as_ptr()) },
+        Ok(())
+    );
+    assert_eq!(dst, src);
+}
+
+#[test_case]
+fn copy_from_unmapped_returns_error() {
+    let mut dst = [0u8; 16];
+    let result = unsafe { memory::copy_from_checked(&mut dst, UNMAPPED as *const u8) };
+    assert_eq!(result, Err(CopyFault { copied: 0 }));
+}
+
+#[test_case]
+fn copy_from_non_canonical_returns_error() {
+    let mut dst = [0u8; 16];
+    let result = unsafe { memory::copy_from_checked(&mut dst, NON_CANONICAL as *const u8) };
+    assert_eq!(result, Err(CopyFault { copied: 0 }));
+}
+
+#[test_case]
+fn partial_copy_reports_copied_bytes() {
+    let region = region::allocate("fixup source", 1, PageTableFlags::WRITABLE).unwrap();
+    let src = (region.end() - 8u64).as_mut_ptr::<u8>();
+    unsafe { src.write_bytes(0xaa, 8) };
+
+    // 後半はガードページに入る
+    let mut dst = [0u8; 16];
+    let result = unsafe { memory::copy_from_checked(&mut dst, src) };
+    assert_eq!(result, Err(CopyFault { copied: 8 }));
+    assert_eq!(dst[..8], [0xaa; 8]);
+    unsafe { region::free(region) };
+}
+
+#[test_case]
+fn copy_to_read_only_returns_error() {
+    let region = region::allocate("read only", 1, PageTableFlags::NO_EXECUTE).unwrap();
+    let result = unsafe { memory::copy_to_checked(region.start().as_mut_ptr(), &[1, 2, 3]) };
+    assert_eq!(result, Err(CopyFault { copied: 0 }));
+    unsafe { region::free(region) };
+}
+
+#[test_case]
+fn lazy_region_is_mapped_during_copy() {
+    let region = region::allocate_lazy("lazy source", 1, PageTableFlags::WRITABLE).unwrap();
+    let mut dst = [0xffu8; 32];
+    let result = unsafe { memory::copy_from_checked(&mut dst, region.start().as_ptr()) };
+    assert_eq!(result, Ok(()));
+    assert_eq!(dst, [0; 32]);
+    unsafe { region::free(region) };
+}
+
+#[test_case]
+fn read_checked_value() {
+    let value = 0x1234_5678_u64;
+    assert_eq!(unsafe { memory::read_checked(&value) }, Ok(value));
+    assert_eq!(
+        unsafe { memory::read_checked(UNMAPPED as *const u64) },
+        Err(CopyFault { copied: 0 })
+    );
+}
+
+#[test_case]
+fn overlapping_fixup_is_rejected() {
+    let start = VirtAddr::new(0x1000);
+    let end = VirtAddr::new(0x2000);
+    assert_eq!(register_fixup(start, end, end), Ok(()));
+    assert_eq!(
+        register_fixup(start + 0x800u64, end + 0x800u64, end),
+        Err(FixupError::Overlapping)
+    );
+    assert_eq!(register_fixup(end, end, end), Err(FixupError::EmptyRange));
+
+    // 外した範囲の例外はもう書き換えられない
+    let fixup = Fixup {
+        start,
+        end,
+        fixup: end,
+    };
+    assert_eq!(unregister_fixup(start), Some(fixup));
+    assert_eq!(search_fixup(start), None);
+    assert_eq!(unregister_fixup(start), None);
+}
@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn allocated_frames_are_unique() {
+    memory::with_kernel_memory(|kernel_memory| {
+        let mut frames = [None::<PhysFrame>; 64];
+        for i in 0..frames.len() {
+            let frame: PhysFrame = kernel_memory.frame_allocator.allocate_frame().unwrap();
+            assert!(!frames[..i].contains(&Some(frame)));
+            frames[i] = Some(frame);
+        }
+        for frame in frames.iter().flatten() {
+            unsafe { kernel_memory.frame_allocator.deallocate_frame(*frame) };
+        }
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    memory::with_kernel_memory(|kernel_memory| {
+        let frame: PhysFrame = kernel_memory.frame_allocator.allocate_frame().unwrap();
+        unsafe { kernel_memory.frame_allocator.deallocate_frame(frame) };
+        assert_eq!(kernel_memory.frame_allocator.allocate_frame(), Some(frame));
+        unsafe { kernel_memory.frame_allocator.deallocate_frame(frame) };
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn unmap_returns_frame() {
+    memory::with_kernel_memory(|kernel_memory| {
+        let page = Page::containing_address(VirtAddr::new(0xdeadbeaf000));
+        let frame: PhysFrame = kernel_memory.frame_allocator.allocate_frame().unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe {
+            kernel_memory
+                .mapper
+                .map_to(page, frame, flags, &mut kernel_memory.frame_allocator)
+                .unwrap()
+                .flush();
+            page.start_address().as_mut_ptr::<u64>().write_volatile(42);
+            kernel_memory.unmap_page(page).unwrap();
+        }
+        // マッピングを外したフレームは次の割り当てで再利用される
+        let reused: PhysFrame = kernel_memory.frame_allocator.allocate_frame().unwrap();
+        assert_eq!(reused, frame);
+        unsafe { kernel_memory.frame_allocator.deallocate_frame(reused) };
+    })
+    .unwrap();
+}
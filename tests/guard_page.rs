@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+
+use blog_os::fmt_buffer::FmtBuffer;
+use blog_os::gdt;
+use blog_os::memory::region;
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    serial_print!("guard_page::overflow_is_reported...\t");
+
+    // カーネルのIDTをそのまま使い，page faultのハンドラの報告を確かめる
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    let buffer = region::allocate_stack("overflowing buffer", 1).unwrap();
+    let ptr = buffer.start().as_mut_ptr::<u8>();
+    // 領域の直前(ガードページ)に書き込む
+    unsafe { ptr.sub(1).write_volatile(1) };
+
+    panic!("Execution continued after guard page hit");
+}
+
+/// ガードページに触れると，カーネルのpage faultのハンドラが領域を報告してpanicする
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = FmtBuffer::<4096>::new();
+    let _ = write!(message, "{}", info);
+    let message = message.as_str();
+
+    // ハンドラはpage fault用のスタックで動いている
+    let marker = 0u8;
+    let on_fault_stack = gdt::ist_stacks().iter().any(|stack| {
+        stack.index == gdt::PAGE_FAULT_IST_INDEX && stack.contains(VirtAddr::from_ptr(&marker))
+    });
+
+    if message.contains("overflow below region `overflowing buffer`")
+        && message.contains("#PF")
+        && on_fault_stack
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        blog_os::hlt_loop();
+    }
+    blog_os::test_panic_handler(info);
+}
@@ -7,21 +7,22 @@
 extern crate alloc;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use blog_os::allocator::HEAP_SIZE;
+use blog_os::allocator::{self, HEAP_SIZE};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    use blog_os::allocator;
     use blog_os::memory::{self, BootInfoFrameAllocator};
     use x86_64::VirtAddr;
     blog_os::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
 
     test_main();
     loop {}
@@ -57,3 +58,108 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
 }
+
+#[test_case]
+fn many_boxes_long_lived() {
+    // 解放されたブロックが再利用されなければ，ヒープを使い切って失敗する
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn freed_block_is_reused() {
+    let first = Box::new(42u64);
+    let first_addr = &*first as *const u64 as usize;
+    drop(first);
+    // 同じサイズクラスの割り当ては直前に解放されたブロックを受け取る
+    let second = Box::new(13u64);
+    assert_eq!(&*second as *const u64 as usize, first_addr);
+}
+
+#[test_case]
+fn block_alignment() {
+    use core::alloc::Layout;
+
+    for &align in &[8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096] {
+        let layout = Layout::from_size_align(8, align).unwrap();
+        let ptr = unsafe { alloc::alloc::alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % align, 0);
+        unsafe { alloc::alloc::dealloc(ptr, layout) };
+    }
+}
+
+#[test_case]
+fn large_allocation_uses_fallback() {
+    // 2048バイトより大きい割り当てはフォールバックアロケータから割り当てられる
+    let mut vec: Vec<u8> = Vec::with_capacity(4096);
+    for i in 0..4096 {
+        vec.push(i as u8);
+    }
+    assert!(vec.iter().enumerate().all(|(i, &v)| v == i as u8));
+}
+
+#[test_case]
+fn heap_grows_on_demand() {
+    // 初期のヒープサイズより大きな割り当ても，ページを追加でマップして成功する
+    let size = HEAP_SIZE * 4;
+    let mut vec: Vec<u8> = Vec::with_capacity(size);
+    vec.resize(size, 0xab);
+    assert!(vec.iter().all(|&v| v == 0xab));
+}
+
+#[test_case]
+fn many_live_boxes_beyond_initial_heap() {
+    // 同時に生きているブロックの合計が初期のヒープサイズを超えても割り当てられる
+    let boxes: Vec<Box<[u8; 1024]>> = (0..HEAP_SIZE / 1024 * 2)
+        .map(|_| Box::new([0u8; 1024]))
+        .collect();
+    assert_eq!(boxes.len(), HEAP_SIZE / 1024 * 2);
+}
+
+#[test_case]
+fn stats_track_allocations() {
+    let before = allocator::stats();
+    let value = Box::new([0u64; 16]);
+    let during = allocator::stats();
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 128);
+    assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+    drop(value);
+    let after = allocator::stats();
+    assert_eq!(after.deallocations, before.deallocations + 1);
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert!(after.largest_free_block <= after.free_bytes);
+    assert!(after.free_bytes <= after.heap_size);
+}
+
+#[test_case]
+fn leak_tracking_reports_outstanding_allocations() {
+    allocator::start_leak_tracking();
+    let freed = Box::new(1u32);
+    let leaked = Box::new(2u64);
+    drop(freed);
+    let report = allocator::stop_leak_tracking();
+
+    assert_eq!(report.len(), 1);
+    let allocation = report.iter().next().unwrap();
+    assert_eq!(allocation.addr, &*leaked as *const u64 as usize);
+    assert_eq!(allocation.layout, core::alloc::Layout::new::<u64>());
+}
+
+#[test_case]
+fn leak_tracking_empty_when_everything_freed() {
+    allocator::start_leak_tracking();
+    {
+        let mut vec = Vec::new();
+        for i in 0..100 {
+            vec.push(Box::new(i));
+        }
+    }
+    let report = allocator::stop_leak_tracking();
+    assert!(report.is_empty(), "{:?}", report);
+}
@@ -0,0 +1,123 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::interrupts::{self, irq, IrqError, PICS};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[no_mangle] // この関数の名前を変えない
+pub extern "C" fn _start() -> ! {
+    blog_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// テストに使う，デバイスのつながっていないIRQ
+const TEST_IRQ: u8 = 5;
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn counting_handler(irq: u8) {
+    assert_eq!(irq, TEST_IRQ);
+    CALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn other_handler(_irq: u8) {}
+
+/// IRQ5のベクタ(PIC_1_OFFSET + 5)にソフトウェア割り込みを起こす
+fn raise_test_irq() {
+    unsafe { asm!("int 37") };
+}
+
+fn pic_masked(irq: u8) -> bool {
+    let masks = unsafe { PICS.lock().read_masks() };
+    let masks = u16::from(masks[0]) | u16::from(masks[1]) << 8;
+    masks & (1 << irq) != 0
+}
+
+#[test_case]
+fn default_handlers_are_registered() {
+    assert!(irq::irq_handler(0).is_some());
+    assert!(irq::irq_handler(1).is_some());
+    assert!(!pic_masked(0));
+    assert!(!pic_masked(1));
+}
+
+#[test_case]
+fn registered_handler_is_called() {
+    interrupts::register_irq(TEST_IRQ, counting_handler).unwrap();
+    assert!(!pic_masked(TEST_IRQ));
+
+    let before = CALLS.load(Ordering::SeqCst);
+    raise_test_irq();
+    assert_eq!(CALLS.load(Ordering::SeqCst), before + 1);
+
+    interrupts::unregister_irq(TEST_IRQ).unwrap();
+}
+
+#[test_case]
+fn unregistered_handler_is_not_called() {
+    interrupts::register_irq(TEST_IRQ, counting_handler).unwrap();
+    interrupts::unregister_irq(TEST_IRQ).unwrap();
+    assert!(pic_masked(TEST_IRQ));
+
+    let before = CALLS.load(Ordering::SeqCst);
+    raise_test_irq();
+    assert_eq!(CALLS.load(Ordering::SeqCst), before);
+}
+
+#[test_case]
+fn double_registration_fails() {
+    interrupts::register_irq(TEST_IRQ, counting_handler).unwrap();
+    assert_eq!(
+        interrupts::register_irq(TEST_IRQ, other_handler),
+        Err(IrqError::AlreadyRegistered(TEST_IRQ))
+    );
+    interrupts::unregister_irq(TEST_IRQ).unwrap();
+    assert_eq!(
+        interrupts::unregister_irq(TEST_IRQ).map(|_| ()),
+        Err(IrqError::NotRegistered(TEST_IRQ))
+    );
+}
+
+#[test_case]
+fn invalid_irq_is_rejected() {
+    assert_eq!(
+        interrupts::register_irq(16, other_handler),
+        Err(IrqError::InvalidIrq(16))
+    );
+}
+
+#[test_case]
+fn cascade_irq_is_rejected() {
+    assert_eq!(
+        interrupts::register_irq(2, other_handler),
+        Err(IrqError::InvalidIrq(2))
+    );
+    assert_eq!(
+        interrupts::unregister_irq(2).map(|_| ()),
+        Err(IrqError::InvalidIrq(2))
+    );
+    // スレーブのPICをつなぐ線はマスクされていない
+    assert!(!pic_masked(2));
+}
+
+#[test_case]
+fn failed_unregister_keeps_mask() {
+    interrupts::set_irq_masked(TEST_IRQ, false);
+    assert_eq!(
+        interrupts::unregister_irq(TEST_IRQ).map(|_| ()),
+        Err(IrqError::NotRegistered(TEST_IRQ))
+    );
+    assert!(!pic_masked(TEST_IRQ));
+    interrupts::set_irq_masked(TEST_IRQ, true);
+}
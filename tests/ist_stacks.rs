@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use blog_os::gdt;
+use blog_os::memory::{self, region, BootInfoFrameAllocator};
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.non_maskable_interrupt
+                .set_handler_fn(test_nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check
+                .set_handler_fn(test_machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
+        idt
+    };
+}
+
+/// NMIのハンドラが自分のISTのスタックで動いたかどうか
+static NMI_ON_IST_STACK: AtomicBool = AtomicBool::new(false);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+
+    blog_os::gdt::init();
+    TEST_IDT.load();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    // 使い切ったスタックの底(すぐ下はガードページ)にスタックポインタを置いて例外を起こす
+    // 今のスタックに積もうとするとページフォルトになり，ハンドラがないので停止する
+    let stack = region::allocate_stack("overflowed stack", 1).unwrap();
+    let bottom = stack.start().as_u64();
+
+    // NMIのハンドラは戻ってくるので，スタックポインタを元に戻して次のISTを試す
+    serial_print!("ist_stacks::nmi_stack_is_used...\t");
+    unsafe {
+        asm!(
+            "mov {saved}, rsp",
+            "mov rsp, {bottom}",
+            "int 2",
+            "mov rsp, {saved}",
+            bottom = in(reg) bottom,
+            saved = out(reg) _,
+        );
+    }
+    assert!(NMI_ON_IST_STACK.load(Ordering::SeqCst));
+    serial_println!("[ok]");
+
+    // マシンチェックのハンドラからは戻れないので，最後に試す
+    serial_print!("ist_stacks::machine_check_stack_is_used...\t");
+    unsafe {
+        asm!(
+            "mov rsp, {bottom}",
+            "int 18",
+            bottom = in(reg) bottom,
+            options(noreturn)
+        );
+    }
+}
+
+/// 呼び出し元が`index`番のISTのスタックで動いているかどうか
+fn on_ist_stack(index: u16) -> bool {
+    let marker = 0u8;
+    gdt::ist_stacks()
+        .iter()
+        .any(|stack| stack.index == index && stack.contains(VirtAddr::from_ptr(&marker)))
+}
+
+extern "x86-interrupt" fn test_nmi_handler(_stack_frame: InterruptStackFrame) {
+    NMI_ON_IST_STACK.store(on_ist_stack(gdt::NMI_IST_INDEX), Ordering::SeqCst);
+}
+
+extern "x86-interrupt" fn test_machine_check_handler(_stack_frame: InterruptStackFrame) -> ! {
+    assert!(on_ist_stack(gdt::MACHINE_CHECK_IST_INDEX));
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info);
+}
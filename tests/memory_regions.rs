@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::allocator::{HEAP_MAX_SIZE, HEAP_START};
+use blog_os::memory::region::{self, GuardSide};
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames()).unwrap()
+}
+
+#[test_case]
+fn allocated_region_is_usable() {
+    let region = region::allocate("test region", 4, PageTableFlags::WRITABLE).unwrap();
+    assert_eq!(region.size(), 4 * 4096);
+    let ptr = region.start().as_mut_ptr::<u64>();
+    let len = region.size() as usize / 8;
+    for i in 0..len {
+        unsafe { ptr.add(i).write_volatile(i as u64) };
+    }
+    for i in 0..len {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, i as u64);
+    }
+    unsafe { region::free(region) };
+}
+
+#[test_case]
+fn free_returns_frames() {
+    let before = free_frames();
+    let region = region::allocate_stack("test stack", 8).unwrap();
+    assert!(free_frames() <= before - 8);
+    unsafe { region::free(region) };
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn regions_are_separated_by_guard_pages() {
+    let a = region::allocate("region a", 1, PageTableFlags::WRITABLE).unwrap();
+    let b = region::allocate("region b", 1, PageTableFlags::WRITABLE).unwrap();
+    assert!(b.start() >= a.end() + 4096u64 || a.start() >= b.end() + 4096u64);
+    unsafe {
+        region::free(a);
+        region::free(b);
+    }
+}
+
+#[test_case]
+fn guard_page_hit_reports_region() {
+    let region = region::allocate_stack("guarded stack", 2).unwrap();
+
+    let below = region::guard_page_hit(region.start() - 8u64).unwrap();
+    assert_eq!(below.region, region);
+    assert_eq!(below.region.name(), "guarded stack");
+    assert_eq!(below.side, GuardSide::Below);
+
+    let above = region::guard_page_hit(region.end()).unwrap();
+    assert_eq!(above.side, GuardSide::Above);
+
+    assert!(region::guard_page_hit(region.start()).is_none());
+    assert!(region::guard_page_hit(region.start() - 4097u64).is_none());
+
+    unsafe { region::free(region) };
+    assert!(region::guard_page_hit(region.start() - 8u64).is_none());
+}
+
+#[test_case]
+fn heap_is_guarded() {
+    let below = region::guard_page_hit(VirtAddr::new(HEAP_START as u64 - 1)).unwrap();
+    assert_eq!(below.region.name(), "kernel heap");
+    let above = region::guard_page_hit(VirtAddr::new((HEAP_START + HEAP_MAX_SIZE) as u64));
+    assert_eq!(above.unwrap().side, GuardSide::Above);
+}
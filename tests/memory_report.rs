@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::report::MemoryMapSummary;
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::{entry_point, BootInfo};
+use conquer_once::spin::OnceCell;
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{FrameDeallocator, PhysFrame};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+static MEMORY_MAP: OnceCell<&'static MemoryMap> = OnceCell::uninit();
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+    MEMORY_MAP.init_once(|| &boot_info.memory_map);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn memory_map() -> &'static MemoryMap {
+    MEMORY_MAP.get().unwrap()
+}
+
+#[test_case]
+fn summary_covers_every_region() {
+    let summary = MemoryMapSummary::new(memory_map());
+    let regions: usize = summary.iter().map(|t| t.regions).sum();
+    assert_eq!(regions, memory_map().iter().count());
+    let bytes: u64 = summary.iter().map(|t| t.bytes).sum();
+    assert_eq!(bytes, summary.total_bytes);
+    assert!(summary.bytes_of(MemoryRegionType::Usable) > 0);
+}
+
+#[test_case]
+fn frame_total_matches_usable_memory() {
+    let summary = MemoryMapSummary::new(memory_map());
+    let usage = memory::frame_usage().unwrap();
+    assert_eq!(
+        usage.total as u64,
+        summary.bytes_of(MemoryRegionType::Usable) / 4096
+    );
+    assert_eq!(usage.used() + usage.free, usage.total);
+}
+
+#[test_case]
+fn frame_usage_tracks_allocation() {
+    use x86_64::structures::paging::FrameAllocator;
+
+    let before = memory::frame_usage().unwrap();
+    let frame: PhysFrame =
+        memory::with_kernel_memory(|m| m.frame_allocator.allocate_frame().unwrap()).unwrap();
+    let during = memory::frame_usage().unwrap();
+    assert_eq!(during.used(), before.used() + 1);
+    assert_eq!(during.free, before.free - 1);
+
+    memory::with_kernel_memory(|m| unsafe { m.frame_allocator.deallocate_frame(frame) });
+    assert_eq!(memory::frame_usage().unwrap(), before);
+}
+
+#[test_case]
+fn print_memory_map_does_not_panic() {
+    memory::print_memory_map(memory_map());
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// VGAテキストバッファの物理アドレス
+const VGA_BUFFER: u64 = 0xb8000;
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames()).unwrap()
+}
+
+fn translate(addr: VirtAddr) -> TranslateResult {
+    memory::with_kernel_memory(|m| m.mapper.translate(addr)).unwrap()
+}
+
+#[test_case]
+fn mmio_maps_device_memory_uncached() {
+    let mmio = unsafe { memory::map_mmio(PhysAddr::new(VGA_BUFFER), 4000) }.unwrap();
+    match translate(mmio.base()) {
+        TranslateResult::Mapped { frame, flags, .. } => {
+            assert_eq!(frame.start_address().as_u64(), VGA_BUFFER);
+            assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+        }
+        other => panic!("mmio is not mapped: {:?}", other),
+    }
+}
+
+#[test_case]
+fn mmio_accesses_physical_range() {
+    let phys_mem_offset = memory::physical_memory_offset().unwrap();
+    let direct = (phys_mem_offset + VGA_BUFFER + 160u64).as_mut_ptr::<u16>();
+
+    let mut mmio = unsafe { memory::map_mmio(PhysAddr::new(VGA_BUFFER + 160), 160) }.unwrap();
+    assert_eq!(mmio.len(), 160);
+    mmio.write::<u16>(0, 0x0f41);
+    assert_eq!(unsafe { direct.read_volatile() }, 0x0f41);
+    unsafe { direct.write_volatile(0x0f42) };
+    assert_eq!(mmio.read::<u16>(0), 0x0f42);
+}
+
+#[test_case]
+fn drop_unmaps_without_freeing_frames() {
+    let mmio = unsafe { memory::map_mmio(PhysAddr::new(VGA_BUFFER), 4096) }.unwrap();
+    let base = mmio.base();
+    let before = free_frames();
+    drop(mmio);
+    assert_eq!(free_frames(), before);
+    assert!(matches!(translate(base), TranslateResult::NotMapped));
+}
@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use blog_os::gdt;
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(test_page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("page_fault_stack::stack_overflow_is_handled_on_ist...\t");
+
+    blog_os::gdt::init();
+    TEST_IDT.load();
+    stack_overflow();
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow(); // 再帰の度にリターンアドレスがプッシュされる
+    volatile::Volatile::new(0).read(); // 末尾最適化をふせぐ
+}
+
+/// スタックがあふれた状態でも，page faultのハンドラは専用のスタックで動く
+extern "x86-interrupt" fn test_page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    let marker = 0u8;
+    let stack = gdt::ist_stacks()
+        .iter()
+        .find(|stack| stack.index == gdt::PAGE_FAULT_IST_INDEX)
+        .unwrap();
+    assert!(stack.contains(VirtAddr::from_ptr(&marker)));
+    // あふれたのはカーネルのスタック(のガードページ)
+    let accessed = Cr2::read();
+    assert!(accessed < stack_frame.stack_pointer + 4096u64);
+    assert!(accessed + 4096u64 > stack_frame.stack_pointer);
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    panic!("stack overflow escalated to a double fault");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info);
+}
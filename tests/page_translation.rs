@@ -0,0 +1,162 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::Translate;
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+/// テストから参照するための物理メモリのオフセット
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn phys_mem_offset() -> VirtAddr {
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed))
+}
+
+fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    unsafe { memory::translate_addr(addr, phys_mem_offset()) }
+}
+
+#[test_case]
+fn translate_physical_memory_mapping() {
+    // bootloaderは全物理メモリを(可能なら2MiBや1GiBのhuge pageで)オフセットの位置にマップする
+    for &phys in &[0x0, 0xb8000, 0x10_0123, 0x1f_fff8, 0x20_1234, 0x3f_ffff] {
+        let virt = phys_mem_offset() + phys;
+        assert_eq!(translate(virt), Some(PhysAddr::new(phys)));
+    }
+}
+
+#[test_case]
+fn translate_matches_offset_page_table() {
+    let heap_value = Box::new(42u64);
+    let addresses = [
+        VirtAddr::from_ptr(&*heap_value),
+        VirtAddr::new(translate as fn(VirtAddr) -> Option<PhysAddr> as u64),
+        phys_mem_offset() + 0x20_0000u64,
+    ];
+    for &addr in &addresses {
+        let expected = memory::with_kernel_memory(|m| m.mapper.translate_addr(addr)).unwrap();
+        assert!(expected.is_some());
+        assert_eq!(translate(addr), expected);
+    }
+}
+
+#[test_case]
+fn translate_unmapped_address() {
+    assert_eq!(translate(VirtAddr::new(0x_1234_5678_9000)), None);
+}
+
+#[test_case]
+fn mapped_ranges_describe_heap() {
+    use blog_os::allocator::HEAP_START;
+    use x86_64::structures::paging::PageTableFlags;
+
+    let heap_start = VirtAddr::new(HEAP_START as u64);
+    let mut heap_range = None;
+    memory::for_each_mapped_range(|range| {
+        if range.contains(heap_start) {
+            heap_range = Some(*range);
+        }
+    });
+    let heap_range = heap_range.expect("heap is not mapped");
+    assert_eq!(heap_range.page_size, 4096);
+    assert!(heap_range.flags.contains(PageTableFlags::WRITABLE));
+    assert!(!heap_range.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+    let offset = heap_start - heap_range.start;
+    assert_eq!(translate(heap_start), Some(heap_range.phys_start + offset));
+}
+
+#[test_case]
+fn mapped_ranges_are_sorted_and_coalesced() {
+    let mut previous: Option<memory::MappedRange> = None;
+    let mut count = 0;
+    memory::for_each_mapped_range(|range| {
+        if let Some(prev) = previous {
+            let prev_end = prev.start.as_u64() + prev.size;
+            assert!(prev_end <= range.start.as_u64());
+            // 隣接していて物理アドレスも連続する同じ種類の範囲は一つにまとめられている
+            assert!(
+                prev_end != range.start.as_u64()
+                    || prev.phys_start.as_u64() + prev.size != range.phys_start.as_u64()
+                    || prev.page_size != range.page_size
+                    || prev.flags != range.flags
+            );
+        }
+        previous = Some(*range);
+        count += 1;
+    });
+    assert!(count > 0);
+}
+
+#[test_case]
+fn dump_page_tables() {
+    memory::dump_page_tables();
+}
+
+#[test_case]
+fn translate_huge_page_with_pat_bit() {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::{
+        Mapper, Page, PageTable, PageTableFlags, PhysFrame, Size2MiB,
+    };
+
+    // どこにも使われていない2MiBページを，既存の2MiBの物理範囲にマップする
+    let page: Page<Size2MiB> = Page::containing_address(VirtAddr::new(0x_5555_5540_0000));
+    let frame: PhysFrame<Size2MiB> = PhysFrame::containing_address(PhysAddr::new(0x20_0000));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+    memory::with_kernel_memory(|m| {
+        unsafe { m.mapper.map_to(page, frame, flags, &mut m.frame_allocator) }
+            .expect("failed to map huge page")
+            .flush();
+    })
+    .unwrap();
+
+    // P2のエントリのビット12(PAT)を立てる
+    let table = |frame: PhysFrame| -> &'static mut PageTable {
+        unsafe { &mut *(phys_mem_offset() + frame.start_address().as_u64()).as_mut_ptr() }
+    };
+    let addr = page.start_address();
+    let p3 = table(Cr3::read().0)[addr.p4_index()].frame().unwrap();
+    let p2 = table(p3)[addr.p3_index()].frame().unwrap();
+    let entry = &mut table(p2)[addr.p2_index()];
+    let entry_flags = entry.flags();
+    entry.set_addr(frame.start_address() + 0x1000u64, entry_flags);
+
+    let translated = translate(addr + 0x1234u64);
+
+    // 元に戻してから外す
+    entry.set_addr(frame.start_address(), entry_flags);
+    memory::with_kernel_memory(|m| m.mapper.unmap(page).expect("failed to unmap").1.flush())
+        .unwrap();
+    assert_eq!(translated, Some(frame.start_address() + 0x1234u64));
+}
@@ -0,0 +1,158 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::allocator::slab::{self, SlabCache};
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames()).unwrap()
+}
+
+#[derive(Debug)]
+struct Object {
+    id: u64,
+    payload: [u64; 7],
+}
+
+static OBJECTS: SlabCache<Object> = SlabCache::new("object");
+
+#[test_case]
+fn values_are_stored_and_read_back() {
+    let a = OBJECTS
+        .alloc(Object {
+            id: 1,
+            payload: [1; 7],
+        })
+        .unwrap();
+    let mut b = OBJECTS
+        .alloc(Object {
+            id: 2,
+            payload: [2; 7],
+        })
+        .unwrap();
+    b.payload[0] = 42;
+    assert_eq!(a.id, 1);
+    assert_eq!(b.id, 2);
+    assert_eq!(b.payload[0], 42);
+    assert_ne!(&*a as *const Object, &*b as *const Object);
+}
+
+static ALIGNED: SlabCache<Aligned> = SlabCache::new("aligned");
+
+#[repr(align(64))]
+struct Aligned(u8);
+
+#[test_case]
+fn objects_are_aligned() {
+    let a = ALIGNED.alloc(Aligned(1)).unwrap();
+    let b = ALIGNED.alloc(Aligned(2)).unwrap();
+    assert_eq!(&*a as *const Aligned as usize % 64, 0);
+    assert_eq!(&*b as *const Aligned as usize % 64, 0);
+    assert_eq!(a.0 + b.0, 3);
+}
+
+static DROP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+struct CountsDrops;
+
+impl Drop for CountsDrops {
+    fn drop(&mut self) {
+        DROP_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+static DROPPING: SlabCache<CountsDrops> = SlabCache::new("dropping");
+
+#[test_case]
+fn drop_runs_destructor() {
+    let before = DROP_COUNT.load(Ordering::Relaxed);
+    drop(DROPPING.alloc(CountsDrops).unwrap());
+    assert_eq!(DROP_COUNT.load(Ordering::Relaxed), before + 1);
+}
+
+static TRACKED: SlabCache<[u64; 16]> = SlabCache::new("tracked");
+
+#[test_case]
+fn slabs_move_between_lists() {
+    let per_slab = TRACKED.usage().objects_per_slab;
+    let ptrs = [(); 2].map(|_| TRACKED.allocate().unwrap());
+    let usage = TRACKED.usage();
+    assert_eq!(usage.objects_in_use, 2);
+    assert_eq!((usage.partial_slabs, usage.full_slabs), (1, 0));
+
+    // 最初のスラブを満杯にする
+    let mut rest = [None; 64];
+    for slot in rest.iter_mut().take(per_slab - 2) {
+        *slot = Some(TRACKED.allocate().unwrap());
+    }
+    let usage = TRACKED.usage();
+    assert_eq!((usage.partial_slabs, usage.full_slabs), (0, 1));
+
+    // 満杯のスラブから1つ返すと部分的になる
+    unsafe { TRACKED.deallocate(ptrs[0]) };
+    let usage = TRACKED.usage();
+    assert_eq!((usage.partial_slabs, usage.full_slabs), (1, 0));
+
+    unsafe { TRACKED.deallocate(ptrs[1]) };
+    for ptr in rest.iter().flatten() {
+        unsafe { TRACKED.deallocate(*ptr) };
+    }
+    let usage = TRACKED.usage();
+    assert_eq!(usage.objects_in_use, 0);
+    assert_eq!((usage.partial_slabs, usage.empty_slabs), (0, 1));
+}
+
+static SHRINKING: SlabCache<u64> = SlabCache::new("shrinking");
+
+#[test_case]
+fn shrink_returns_frames() {
+    let before = free_frames();
+    let value = SHRINKING.alloc(7).unwrap();
+    assert_eq!(free_frames(), before - 1);
+    drop(value);
+    assert_eq!(SHRINKING.usage().empty_slabs, 1);
+    SHRINKING.shrink();
+    assert_eq!(SHRINKING.usage().slabs(), 0);
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn usage_is_reported_for_used_caches() {
+    let _value = SHRINKING.alloc(1).unwrap();
+    let mut found = None;
+    slab::for_each_cache(|usage| {
+        if usage.name == "shrinking" {
+            found = Some(*usage);
+        }
+    });
+    assert_eq!(found.unwrap().objects_in_use, 1);
+    blog_os::allocator::print_usage();
+}
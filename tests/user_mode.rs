@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::gdt;
+use blog_os::memory::address_space::{self, USER_SPACE_START};
+use blog_os::memory::{self, AddressSpace, BootInfoFrameAllocator};
+use blog_os::usermode;
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+// ユーザモードで動かす小さなプログラム．ユーザページにコピーして実行する
+global_asm!(
+    ".pushsection .rodata.user_programs, \"a\"",
+    // CSをそのまま終了コードにする
+    ".global user_read_cs",
+    "user_read_cs:",
+    "    mov rax, cs",
+    "    int 0x80",
+    ".global user_read_cs_end",
+    "user_read_cs_end:",
+    // スタックを使って6 * 7を計算する
+    ".global user_multiply",
+    "user_multiply:",
+    "    push 6",
+    "    pop rax",
+    "    imul rax, rax, 7",
+    "    int 0x80",
+    ".global user_multiply_end",
+    "user_multiply_end:",
+    ".popsection",
+);
+
+extern "C" {
+    static user_read_cs: u8;
+    static user_read_cs_end: u8;
+    static user_multiply: u8;
+    static user_multiply_end: u8;
+}
+
+fn code_page() -> Page {
+    Page::containing_address(VirtAddr::new(USER_SPACE_START))
+}
+
+fn stack_page() -> Page {
+    code_page() + 16
+}
+
+/// `[start, end)`の機械語をユーザページに置いてユーザモードで実行し，終了コードを返す
+fn run_program(start: &u8, end: &u8) -> u64 {
+    let code = unsafe {
+        let start = start as *const u8;
+        core::slice::from_raw_parts(start, end as *const u8 as usize - start as usize)
+    };
+
+    let mut address_space = AddressSpace::new().unwrap();
+    // コードのページはユーザから書き込めないようにし，物理メモリのマッピング越しに書く
+    let code_frame = address_space
+        .map_user_page(code_page(), PageTableFlags::empty())
+        .unwrap();
+    address_space
+        .map_user_page(
+            stack_page(),
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .unwrap();
+    let phys_offset = memory::with_kernel_memory(|m| m.mapper.phys_offset()).unwrap();
+    unsafe {
+        let dst: *mut u8 = (phys_offset + code_frame.start_address().as_u64()).as_mut_ptr();
+        dst.copy_from_nonoverlapping(code.as_ptr(), code.len());
+    }
+
+    unsafe { address_space.activate() };
+    let stack_top = stack_page().start_address() + 4096u64;
+    let exit_code = unsafe { usermode::run_user_mode(code_page().start_address(), stack_top) };
+    unsafe { address_space::activate_kernel() };
+    exit_code
+}
+
+#[test_case]
+fn user_code_runs_in_ring_3() {
+    let cs = run_program(unsafe { &user_read_cs }, unsafe { &user_read_cs_end });
+    assert_eq!(cs & 3, 3);
+    assert_eq!(cs, u64::from(gdt::user_selectors().code.0));
+}
+
+#[test_case]
+fn user_code_can_use_its_stack() {
+    let result = run_program(unsafe { &user_multiply }, unsafe { &user_multiply_end });
+    assert_eq!(result, 42);
+}
+
+#[test_case]
+fn kernel_state_is_restored_after_return() {
+    use x86_64::instructions::interrupts;
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
+
+    let cs = CS::get_reg();
+    let ss = SS::get_reg();
+    run_program(unsafe { &user_multiply }, unsafe { &user_multiply_end });
+    assert_eq!(CS::get_reg(), cs);
+    assert_eq!(SS::get_reg(), ss);
+    assert!(interrupts::are_enabled());
+    // 戻った後もタイマ割り込みは届く
+    let start = blog_os::time::ticks();
+    blog_os::time::wait_ticks(2);
+    assert!(blog_os::time::ticks() > start);
+}
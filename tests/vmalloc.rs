@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::region;
+use blog_os::memory::vmalloc::VMALLOC_AREA;
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames()).unwrap()
+}
+
+#[test_case]
+fn large_buffer_is_usable() {
+    let size = 1024 * 1024; // ヒープ(100KiB)には入らない大きさ
+    let addr = memory::vmalloc(size).unwrap();
+    assert!(VMALLOC_AREA.contains(&addr.as_u64()));
+
+    let buffer = unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr::<u64>(), size / 8) };
+    for (i, value) in buffer.iter_mut().enumerate() {
+        *value = i as u64;
+    }
+    assert!(buffer
+        .iter()
+        .enumerate()
+        .all(|(i, &value)| value == i as u64));
+    unsafe { memory::vfree(addr) };
+}
+
+#[test_case]
+fn vfree_returns_frames() {
+    let before = free_frames();
+    let addr = memory::vmalloc(64 * 4096).unwrap();
+    assert!(free_frames() <= before - 64);
+    unsafe { memory::vfree(addr) };
+    // ページテーブル用のフレームは解放されない
+    assert!(free_frames() >= before - 3);
+
+    let again = memory::vmalloc(64 * 4096).unwrap();
+    let after_again = free_frames();
+    unsafe { memory::vfree(again) };
+    assert_eq!(free_frames(), after_again + 64);
+}
+
+#[test_case]
+fn size_is_rounded_up_to_pages() {
+    let addr = memory::vmalloc(1).unwrap();
+    let region = region::find(addr).unwrap();
+    assert_eq!(region.size(), 4096);
+    unsafe { memory::vfree(addr) };
+}
+
+#[test_case]
+fn allocations_are_guarded() {
+    let a = memory::vmalloc(4096).unwrap();
+    let b = memory::vmalloc(4096).unwrap();
+    assert_ne!(a, b);
+    let hit = region::guard_page_hit(a + 4096u64).unwrap();
+    assert_eq!(hit.region.name(), "vmalloc");
+    unsafe {
+        memory::vfree(a);
+        memory::vfree(b);
+    }
+}
@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::allocator::HEAP_START;
+use blog_os::memory::region;
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// `addr`を含むページの(末端のエントリの)フラグを返す
+fn flags(addr: VirtAddr) -> PageTableFlags {
+    match memory::with_kernel_memory(|m| m.mapper.translate(addr)).unwrap() {
+        TranslateResult::Mapped { flags, .. } => flags,
+        other => panic!("{:?} is not mapped: {:?}", addr, other),
+    }
+}
+
+#[test_case]
+fn no_execute_is_enabled() {
+    assert!(Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE));
+}
+
+#[test_case]
+fn no_page_is_writable_and_executable() {
+    if let Some(range) = memory::find_writable_executable() {
+        panic!("writable and executable mapping: {}", range);
+    }
+}
+
+#[test_case]
+fn heap_is_not_executable() {
+    assert!(flags(VirtAddr::new(HEAP_START as u64)).contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn stacks_are_not_executable() {
+    let local = 0u64;
+    assert!(flags(VirtAddr::from_ptr(&local)).contains(PageTableFlags::NO_EXECUTE));
+
+    let stack = region::allocate_stack("test stack", 1).unwrap();
+    assert!(flags(stack.start()).contains(PageTableFlags::NO_EXECUTE));
+    unsafe { region::free(stack) };
+}
+
+#[test_case]
+fn kernel_text_is_read_only() {
+    let text = VirtAddr::new(flags as fn(VirtAddr) -> PageTableFlags as usize as u64);
+    let flags = flags(text);
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn writable_regions_are_not_executable() {
+    // 呼び出し元がNO_EXECUTEを指定しなくても，書き込み可能なページは実行できない
+    let eager = region::allocate("writable region", 1, PageTableFlags::WRITABLE).unwrap();
+    let lazy = region::allocate_lazy("writable lazy region", 1, PageTableFlags::WRITABLE).unwrap();
+    unsafe { lazy.start().as_mut_ptr::<u8>().write_volatile(1) };
+
+    assert!(flags(eager.start()).contains(PageTableFlags::NO_EXECUTE));
+    assert!(flags(lazy.start()).contains(PageTableFlags::NO_EXECUTE));
+    if let Some(range) = memory::find_writable_executable() {
+        panic!("writable and executable mapping: {}", range);
+    }
+    unsafe {
+        region::free(eager);
+        region::free(lazy);
+    }
+}
+
+#[test_case]
+fn kernel_data_stays_writable() {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static DATA: AtomicU64 = AtomicU64::new(1);
+    DATA.fetch_add(1, Ordering::SeqCst);
+    let flags = flags(VirtAddr::from_ptr(&DATA));
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+}